@@ -0,0 +1,615 @@
+use crate::transfer::fetch_text;
+use base64::prelude::{BASE64_STANDARD, Engine};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The hash functions a download can be checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl Algorithm {
+    /// The name used in front of the digest, like the `sha256` in `sha256:<hex>`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Sha1 => "sha1",
+            Self::Md5 => "md5",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// How many hex characters a digest from this algorithm has.
+    fn hex_len(&self) -> usize {
+        match self {
+            Self::Sha256 | Self::Blake3 => 64,
+            Self::Sha512 => 128,
+            Self::Sha1 => 40,
+            Self::Md5 => 32,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            "sha1" => Ok(Self::Sha1),
+            "md5" => Ok(Self::Md5),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(format!(
+                "Unknown checksum algorithm `{name}`, expected sha256, sha512, sha1, md5 or blake3"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Algorithm {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+impl From<Algorithm> for String {
+    fn from(algorithm: Algorithm) -> Self {
+        algorithm.name().to_string()
+    }
+}
+
+/// The digest a download is expected to have, written as `<algorithm>:<hex digest>`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    /// The lowercase hex digest.
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Whether `digest` is the one this checksum expects.
+    pub fn matches(&self, digest: &str) -> bool {
+        self.digest.eq_ignore_ascii_case(digest)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((algorithm, digest)) = value.trim().split_once(':') else {
+            return Err(format!(
+                "Checksum `{value}` should look like `sha256:<hex digest>`"
+            ));
+        };
+        let algorithm = algorithm.parse::<Algorithm>()?;
+        let digest = digest.trim().to_ascii_lowercase();
+
+        if digest.len() != algorithm.hex_len() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "`{digest}` is not a {} digest, expected {} hex characters",
+                algorithm.name(),
+                algorithm.hex_len()
+            ));
+        }
+
+        Ok(Self { algorithm, digest })
+    }
+}
+
+impl TryFrom<String> for Checksum {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Checksum> for String {
+    fn from(checksum: Checksum) -> Self {
+        checksum.to_string()
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.digest)
+    }
+}
+
+/// The digest of every `length` byte piece of a file, so a damaged download can be narrowed
+/// down to the pieces that have to be fetched again.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Pieces {
+    pub algorithm: Algorithm,
+    pub length: u64,
+    /// The lowercase hex digest of each piece, in order. The last piece can be shorter.
+    pub digests: Vec<String>,
+}
+
+impl Pieces {
+    /// The pieces of the file at `path` that don't match their digest.
+    pub fn damaged(&self, path: impl AsRef<Path>) -> io::Result<Vec<usize>> {
+        let mut file = File::open(path)?;
+        let mut damaged = Vec::new();
+
+        for (index, expected) in self.digests.iter().enumerate() {
+            let mut hasher = Hasher::new(self.algorithm);
+            hasher.update_from((&mut file).take(self.length))?;
+            if !expected.eq_ignore_ascii_case(&hasher.finalize()) {
+                damaged.push(index);
+            }
+        }
+
+        Ok(damaged)
+    }
+}
+
+/// Hashes a download a chunk at a time as it is written.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Self::Sha512(Sha512::new()),
+            Algorithm::Sha1 => Self::Sha1(Sha1::new()),
+            Algorithm::Md5 => Self::Md5(Md5::new()),
+            Algorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Sha256(_) => Algorithm::Sha256,
+            Self::Sha512(_) => Algorithm::Sha512,
+            Self::Sha1(_) => Algorithm::Sha1,
+            Self::Md5(_) => Algorithm::Md5,
+            Self::Blake3(_) => Algorithm::Blake3,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Feeds everything `reader` has into the hash.
+    pub fn update_from(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => return Ok(()),
+                read => self.update(&buffer[..read]),
+            }
+        }
+    }
+
+    /// The lowercase hex digest of everything hashed so far.
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Self::Sha512(hasher) => to_hex(&hasher.finalize()),
+            Self::Sha1(hasher) => to_hex(&hasher.finalize()),
+            Self::Md5(hasher) => to_hex(&hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hashes a file that is already on disk.
+pub fn digest_file(algorithm: Algorithm, path: impl AsRef<Path>) -> io::Result<String> {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update_from(File::open(path)?)?;
+
+    Ok(hasher.finalize())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Reads the digests a server advertises in a response header: `Digest` (RFC 3230),
+/// `Repr-Digest` and `Content-Digest` (RFC 9530), `Content-MD5`, `x-goog-hash`,
+/// `x-amz-checksum-*` and `X-Checksum-*`. Algorithms that can't be checked are left out.
+pub fn parse_digest_header(name: &str, value: &str) -> Vec<Checksum> {
+    let name = name.trim().to_ascii_lowercase();
+
+    match name.as_str() {
+        "digest" | "repr-digest" | "content-digest" | "x-goog-hash" => value
+            .split(',')
+            .filter_map(|item| {
+                let (algorithm, digest) = item.split_once('=')?;
+                let algorithm = match algorithm.trim().to_ascii_lowercase().as_str() {
+                    // RFC 3230 calls SHA-1 just "SHA"
+                    "sha" => Algorithm::Sha1,
+                    algorithm => algorithm.parse().ok()?,
+                };
+                // RFC 9530 wraps the base64 in colons
+                from_base64(algorithm, digest.trim().trim_matches(':'))
+            })
+            .collect(),
+        "content-md5" => from_base64(Algorithm::Md5, value.trim())
+            .into_iter()
+            .collect(),
+        name => {
+            if let Some(algorithm) = name.strip_prefix("x-amz-checksum-") {
+                algorithm
+                    .parse()
+                    .ok()
+                    .and_then(|algorithm| from_base64(algorithm, value.trim()))
+                    .into_iter()
+                    .collect()
+            } else if let Some(algorithm) = name.strip_prefix("x-checksum-") {
+                format!("{algorithm}:{}", value.trim())
+                    .parse()
+                    .into_iter()
+                    .collect()
+            } else {
+                Vec::new()
+            }
+        }
+    }
+}
+
+fn from_base64(algorithm: Algorithm, digest: &str) -> Option<Checksum> {
+    let bytes = BASE64_STANDARD.decode(digest).ok()?;
+    format!("{}:{}", algorithm.name(), to_hex(&bytes))
+        .parse()
+        .ok()
+}
+
+/// The entries of a checksum file like `SHA256SUMS`, in either the GNU `<digest>  <name>`
+/// format or the BSD `SHA256 (<name>) = <digest>` one.
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumList {
+    /// Where the list came from, for error messages.
+    pub source: String,
+    entries: HashMap<String, Checksum>,
+}
+
+impl ChecksumList {
+    /// Reads a checksum file from a local path, or downloads it if `source` is a URL.
+    pub fn load(
+        source: &str,
+        cookie: Option<&str>,
+        headers: &[String],
+    ) -> Result<Self, Box<dyn Error>> {
+        let text = if source.contains("://") {
+            fetch_text(source, cookie, headers)?
+        } else {
+            fs::read_to_string(source)?
+        };
+
+        Self::parse(source, &text)
+    }
+
+    pub fn parse(source: &str, text: &str) -> Result<Self, Box<dyn Error>> {
+        // GNU style lines don't say which algorithm they use, so go by the file's name and
+        // then the digest's length. BLAKE3 digests are the same length as SHA-256 ones.
+        let file_name = source
+            .rsplit('/')
+            .next()
+            .unwrap_or(source)
+            .to_ascii_lowercase();
+        let named_algorithm = if file_name.contains("blake3")
+            || file_name.starts_with("b3")
+            || file_name.ends_with(".b3")
+        {
+            Some(Algorithm::Blake3)
+        } else {
+            [
+                Algorithm::Sha256,
+                Algorithm::Sha512,
+                Algorithm::Sha1,
+                Algorithm::Md5,
+            ]
+            .into_iter()
+            .find(|algorithm| file_name.contains(algorithm.name()))
+        };
+
+        let mut entries: HashMap<String, Checksum> = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (checksum, name) = parse_bsd_line(line)
+                .or_else(|| parse_gnu_line(line, named_algorithm))
+                .ok_or_else(|| {
+                    format!("{source} line {}: not a checksum line: {line}", number + 1)
+                })?;
+            let checksum = checksum.map_err(|e| format!("{source} line {}: {e}", number + 1))?;
+
+            // A file hashed with more than one algorithm is checked against the first, but two
+            // digests of the same kind can't both be right
+            match entries.get(&name) {
+                Some(listed) if listed.algorithm == checksum.algorithm && *listed != checksum => {
+                    return Err(format!(
+                        "{source} line {}: {name} is listed again with a different digest",
+                        number + 1
+                    )
+                    .into());
+                }
+                Some(_) => {}
+                None => {
+                    entries.insert(name, checksum);
+                }
+            }
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            entries,
+        })
+    }
+
+    /// The checksum listed for `file_name`. Entries with a directory in front still match
+    /// on their last path component, unless more than one of them does.
+    pub fn get(&self, file_name: &str) -> Result<Option<&Checksum>, String> {
+        if let Some(checksum) = self.entries.get(file_name) {
+            return Ok(Some(checksum));
+        }
+
+        let mut matches = self
+            .entries
+            .iter()
+            .filter(|(name, _)| name.rsplit('/').next() == Some(file_name))
+            .collect::<Vec<_>>();
+        if matches.len() > 1 {
+            let mut names = matches
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            names.sort();
+            return Err(format!(
+                "{file_name} matches more than one file in {}: {}",
+                self.source,
+                names.join(", ")
+            ));
+        }
+
+        Ok(matches.pop().map(|(_, checksum)| checksum))
+    }
+}
+
+/// Parses `SHA256 (name) = digest`.
+fn parse_bsd_line(line: &str) -> Option<(Result<Checksum, String>, String)> {
+    let (algorithm, rest) = line.split_once(" (")?;
+    let (name, digest) = rest.rsplit_once(") = ")?;
+    let checksum = format!("{algorithm}:{digest}").parse();
+
+    Some((checksum, clean_name(name)))
+}
+
+/// Parses `digest  name`, or `digest *name` for files hashed in binary mode.
+fn parse_gnu_line(
+    line: &str,
+    algorithm: Option<Algorithm>,
+) -> Option<(Result<Checksum, String>, String)> {
+    let (digest, name) = line.split_once(char::is_whitespace)?;
+    let name = name.trim_start();
+    let name = name.strip_prefix('*').unwrap_or(name);
+    if name.is_empty() {
+        return None;
+    }
+
+    let algorithm = algorithm
+        .filter(|algorithm| algorithm.hex_len() == digest.len())
+        .or(match digest.len() {
+            64 => Some(Algorithm::Sha256),
+            128 => Some(Algorithm::Sha512),
+            40 => Some(Algorithm::Sha1),
+            32 => Some(Algorithm::Md5),
+            _ => None,
+        });
+    let checksum = match algorithm {
+        Some(algorithm) => format!("{}:{digest}", algorithm.name()).parse(),
+        None => Err(format!("can't tell which algorithm made `{digest}`")),
+    };
+
+    Some((checksum, clean_name(name)))
+}
+
+fn clean_name(name: &str) -> String {
+    name.strip_prefix("./").unwrap_or(name).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    fn checksum(algorithm: Algorithm, digest: &str) -> Checksum {
+        Checksum {
+            algorithm,
+            digest: digest.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_gnu_and_bsd_lines() {
+        let text = format!(
+            "# made by sha256sum\n\n{SHA256}  text.txt\n{SHA256} *binary.iso\n{SHA256}  ./dotted.tar\nSHA256 (bsd.img) = {SHA256}\nMD5 (old.zip) = {MD5}\n"
+        );
+        let list = ChecksumList::parse("SHA256SUMS", &text).unwrap();
+
+        for file_name in ["text.txt", "binary.iso", "dotted.tar", "bsd.img"] {
+            assert_eq!(
+                list.get(file_name),
+                Ok(Some(&checksum(Algorithm::Sha256, SHA256))),
+                "{file_name}"
+            );
+        }
+        assert_eq!(
+            list.get("old.zip"),
+            Ok(Some(&checksum(Algorithm::Md5, MD5)))
+        );
+        assert_eq!(list.get("missing.txt"), Ok(None));
+    }
+
+    #[test]
+    fn goes_by_the_file_name_for_blake3() {
+        let list =
+            ChecksumList::parse("https://example.com/B3SUMS", &format!("{SHA256}  a")).unwrap();
+        assert_eq!(list.get("a").unwrap().unwrap().algorithm, Algorithm::Blake3);
+
+        let list = ChecksumList::parse("SHA256SUMS", &format!("{SHA256}  a")).unwrap();
+        assert_eq!(list.get("a").unwrap().unwrap().algorithm, Algorithm::Sha256);
+    }
+
+    #[test]
+    fn refuses_lines_that_are_not_checksums() {
+        for text in [
+            "just some words",
+            "e3b0c442  too-short.txt",
+            SHA256,
+            &format!("SHA256 (broken.img) = {MD5}"),
+            &format!("WHIRLPOOL (unknown.img) = {SHA256}"),
+        ] {
+            assert!(ChecksumList::parse("SUMS", text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_a_name_listed_twice_with_different_digests() {
+        let other = "f".repeat(64);
+        let text = format!("{SHA256}  a.iso\n{MD5}  b.iso\n{other}  a.iso\n");
+        let error = ChecksumList::parse("SUMS", &text).unwrap_err().to_string();
+        assert!(error.starts_with("SUMS line 3: a.iso"), "{error}");
+
+        // The same digest again, or one from another algorithm, is fine
+        let text = format!("{SHA256}  a.iso\n{SHA256} *a.iso\nMD5 (a.iso) = {MD5}\n");
+        let list = ChecksumList::parse("SUMS", &text).unwrap();
+        assert_eq!(
+            list.get("a.iso"),
+            Ok(Some(&checksum(Algorithm::Sha256, SHA256)))
+        );
+    }
+
+    #[test]
+    fn matches_entries_with_a_directory_by_their_name() {
+        let text = format!("{SHA256}  linux/tool\n{MD5}  docs/readme.md\n{SHA256}  tool\n");
+        let list = ChecksumList::parse("SUMS", &text).unwrap();
+
+        assert_eq!(
+            list.get("readme.md"),
+            Ok(Some(&checksum(Algorithm::Md5, MD5)))
+        );
+        // An exact name wins over the ones with a directory in front
+        assert_eq!(
+            list.get("tool"),
+            Ok(Some(&checksum(Algorithm::Sha256, SHA256)))
+        );
+    }
+
+    #[test]
+    fn refuses_a_name_listed_in_more_than_one_directory() {
+        let text = format!("{SHA256}  linux/tool\n{MD5}  darwin/tool\n");
+        let list = ChecksumList::parse("SUMS", &text).unwrap();
+
+        let error = list.get("tool").unwrap_err();
+        assert!(error.contains("darwin/tool, linux/tool"), "{error}");
+        assert_eq!(
+            list.get("linux/tool"),
+            Ok(Some(&checksum(Algorithm::Sha256, SHA256)))
+        );
+    }
+
+    #[test]
+    fn reads_digest_headers() {
+        let sha256 = checksum(Algorithm::Sha256, SHA256);
+        let md5 = checksum(Algorithm::Md5, MD5);
+        let sha1 = checksum(Algorithm::Sha1, "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+
+        assert_eq!(
+            parse_digest_header(
+                "Digest",
+                "SHA=2jmj7l5rSw0yVb/vlWAYkK/YBwk=, SHA-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            ),
+            vec![sha1, sha256.clone()]
+        );
+        assert_eq!(
+            parse_digest_header(
+                "Repr-Digest",
+                "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:"
+            ),
+            vec![sha256.clone()]
+        );
+        assert_eq!(
+            parse_digest_header(
+                "x-goog-hash",
+                "crc32c=AAAAAA==, md5=1B2M2Y8AsgTpgAmY7PhCfg=="
+            ),
+            vec![md5.clone()]
+        );
+        assert_eq!(
+            parse_digest_header("Content-MD5", "1B2M2Y8AsgTpgAmY7PhCfg=="),
+            vec![md5]
+        );
+        assert_eq!(
+            parse_digest_header(
+                "x-amz-checksum-sha256",
+                "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            ),
+            vec![sha256.clone()]
+        );
+        assert_eq!(
+            parse_digest_header("X-Checksum-Sha256", SHA256),
+            vec![sha256]
+        );
+    }
+
+    #[test]
+    fn leaves_out_digests_that_cannot_be_checked() {
+        for (name, value) in [
+            ("Digest", "unixsum=30637"),
+            ("Digest", "SHA-256=not base64!"),
+            ("Digest", "SHA-256=AAAA"),
+            ("Digest", "no equals sign"),
+            ("Content-MD5", ""),
+            ("x-amz-checksum-crc32", "AAAAAA=="),
+            ("X-Checksum-Sha1", "abc"),
+            ("ETag", "\"d41d8cd98f00b204e9800998ecf8427e\""),
+        ] {
+            assert_eq!(parse_digest_header(name, value), vec![], "{name}: {value}");
+        }
+    }
+}
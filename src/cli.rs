@@ -1,4 +1,9 @@
-use clap::{Parser, Subcommand};
+use crate::{
+    checksum::Checksum, conflict::ConflictPolicy, download::DownloadOptions, events::Output,
+    naming::check_file_name,
+};
+use clap::{Args, Parser, Subcommand};
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 #[command(about, author, long_about = None)]
@@ -36,10 +41,48 @@ pub enum Commands {
             short = 'n',
             long,
             num_args = 0..=1,
+            value_parser = check_file_name,
             help = "The name for the file being downloaded."
         )]
         file_name: Option<String>,
-        /// The download link.
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "Split each file into this many byte ranges downloaded at the same time."
+        )]
+        segments: usize,
+        #[arg(
+            long,
+            help = "The digest the file must match, e.g. sha256:<hex>. Also takes sha512, sha1, md5 and blake3."
+        )]
+        checksum: Option<Checksum>,
+        #[arg(
+            long,
+            help = "A checksum file like SHA256SUMS, as a URL or a path, to verify the file against."
+        )]
+        checksums_from: Option<String>,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[arg(
+            long,
+            default_value_t = ConflictPolicy::Rename,
+            help = "What to do when the file is already there: overwrite, skip, rename (to `file (1).iso`), resume or fail."
+        )]
+        on_conflict: ConflictPolicy,
+        #[arg(
+            long,
+            value_parser = parse_size,
+            help = "The biggest file to download, e.g. 700M or 4G. Bigger files are refused before anything is written."
+        )]
+        max_size: Option<u64>,
+        #[arg(
+            long = "mirror",
+            help = "Another URL with the same file. Give it more than once for more mirrors. The fastest is used, switching to another if it fails or stalls."
+        )]
+        mirrors: Vec<String>,
+        /// The download link, or a Metalink document (.meta4 or .metalink) as a URL or a path
+        /// to download the files it describes.
         url: String,
     },
     /// Download multiple files at once.
@@ -47,7 +90,7 @@ pub enum Commands {
         #[arg(
             short,
             long,
-            num_args = 0..1,
+            num_args = 0..=1,
             help = "Use a cookie file for more complex downloads."
         )]
         cookie: Option<String>,
@@ -69,9 +112,60 @@ pub enum Commands {
             short = 'n',
             long,
             num_args = 0..=1000,
+            value_parser = check_file_name,
             help = "The file names to save each file to. Note: Keep them in the same order as the URLs or they will be misnamed."
         )]
         file_names: Option<Vec<String>>,
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "Split each file into this many byte ranges downloaded at the same time."
+        )]
+        segments: usize,
+        #[arg(
+            short,
+            long,
+            default_value_t = 4,
+            help = "How many files to download at the same time."
+        )]
+        jobs: usize,
+        #[arg(
+            long = "checksum",
+            help = "The digest a file must match, e.g. sha256:<hex>. Give one per URL, in the same order as the URLs."
+        )]
+        checksums: Option<Vec<Checksum>>,
+        #[arg(
+            long,
+            help = "A checksum file like SHA256SUMS, as a URL or a path, to verify the files against."
+        )]
+        checksums_from: Option<String>,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[arg(
+            long,
+            value_parser = parse_rate,
+            help = "The most bytes per second all files can download at together, e.g. 10M. Type a new rate and press Enter while downloading to change it."
+        )]
+        total_limit_rate: Option<u64>,
+        #[arg(
+            long,
+            default_value_t = ConflictPolicy::Rename,
+            help = "What to do when the file is already there: overwrite, skip, rename (to `file (1).iso`), resume or fail."
+        )]
+        on_conflict: ConflictPolicy,
+        #[arg(
+            long,
+            value_parser = parse_size,
+            help = "The biggest file to download, e.g. 700M or 4G. Bigger files are refused before anything is written."
+        )]
+        max_size: Option<u64>,
+        #[arg(
+            short,
+            long,
+            help = "A file with one download link per line, or - to read them from standard input. Indented out=, dir=, header=, checksum= and cookie= lines under a link apply to it alone. Blank lines and lines starting with # are skipped."
+        )]
+        input_file: Option<String>,
         /// The list of download links separated by a space.
         urls: Vec<String>,
     },
@@ -85,7 +179,335 @@ pub enum Commands {
             help = "Resume multiple downloads at once."
         )]
         multi: Option<bool>,
+        #[arg(
+            short,
+            long,
+            num_args = 0..=1,
+            help = "Use a cookie file."
+        )]
+        cookie: Option<String>,
+        #[arg(
+            short = 'H',
+            long,
+            num_args = 0..=20,
+            help = "Enter header arguments for more complex downloads."
+        )]
+        header_args: Option<Vec<String>>,
+        #[arg(
+            short,
+            long,
+            default_value_t = 4,
+            help = "How many files to download at the same time."
+        )]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[arg(
+            long,
+            value_parser = parse_rate,
+            help = "The most bytes per second all files can download at together, e.g. 10M. Type a new rate and press Enter while downloading to change it."
+        )]
+        total_limit_rate: Option<u64>,
+        #[arg(
+            long,
+            default_value_t = ConflictPolicy::Rename,
+            help = "What to do when the file is already there: overwrite, skip, rename (to `file (1).iso`), resume or fail."
+        )]
+        on_conflict: ConflictPolicy,
         /// A single download link; multiple download links separated by a space if `--multi` is present.
         url: Vec<String>,
     },
+    /// Show what downloading a URL would do, without downloading it.
+    Info {
+        #[arg(
+            short,
+            long,
+            num_args = 0..=1,
+            help = "Use a cookie file."
+        )]
+        cookie: Option<String>,
+        #[arg(
+            short = 'H',
+            long,
+            num_args = 0..=20,
+            help = "Enter header arguments for more complex downloads."
+        )]
+        header_args: Option<Vec<String>>,
+        #[arg(long, help = "Print the info as JSON.")]
+        json: bool,
+        /// The download link.
+        url: String,
+    },
+    /// Download the files listed in a TOML or YAML manifest that are missing or have changed.
+    Sync {
+        #[arg(
+            short,
+            long,
+            num_args = 0..=1,
+            help = "Use a cookie file for more complex downloads."
+        )]
+        cookie: Option<String>,
+        #[arg(
+            short = 'H',
+            long,
+            num_args = 0..=20,
+            help = "Enter header arguments for more complex downloads."
+        )]
+        header_args: Option<Vec<String>>,
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "Split each file into this many byte ranges downloaded at the same time."
+        )]
+        segments: usize,
+        #[arg(
+            short,
+            long,
+            default_value_t = 4,
+            help = "How many files to download at the same time."
+        )]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[arg(
+            long,
+            value_parser = parse_rate,
+            help = "The most bytes per second all files can download at together, e.g. 10M. Type a new rate and press Enter while downloading to change it."
+        )]
+        total_limit_rate: Option<u64>,
+        #[arg(
+            long,
+            help = "Remove files downloaded for the manifest that it no longer lists. The manifest must set a destination."
+        )]
+        prune: bool,
+        #[arg(
+            long,
+            help = "Only report which files are missing or changed, without downloading or removing anything."
+        )]
+        dry_run: bool,
+        /// The manifest, a .toml file or a .yaml or .yml file.
+        manifest: String,
+    },
+    /// Download the files in a manifest and record their final URL, size, ETag and SHA-256 in
+    /// download_it.lock next to it.
+    Lock {
+        #[arg(
+            short,
+            long,
+            num_args = 0..=1,
+            help = "Use a cookie file for more complex downloads."
+        )]
+        cookie: Option<String>,
+        #[arg(
+            short = 'H',
+            long,
+            num_args = 0..=20,
+            help = "Enter header arguments for more complex downloads."
+        )]
+        header_args: Option<Vec<String>>,
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "Split each file into this many byte ranges downloaded at the same time."
+        )]
+        segments: usize,
+        #[arg(
+            short,
+            long,
+            default_value_t = 4,
+            help = "How many files to download at the same time."
+        )]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        /// The manifest, a .toml file or a .yaml or .yml file.
+        manifest: String,
+    },
+    /// Download the files in a manifest that are missing or have changed.
+    Fetch {
+        #[arg(
+            short,
+            long,
+            num_args = 0..=1,
+            help = "Use a cookie file for more complex downloads."
+        )]
+        cookie: Option<String>,
+        #[arg(
+            short = 'H',
+            long,
+            num_args = 0..=20,
+            help = "Enter header arguments for more complex downloads."
+        )]
+        header_args: Option<Vec<String>>,
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "Split each file into this many byte ranges downloaded at the same time."
+        )]
+        segments: usize,
+        #[arg(
+            short,
+            long,
+            default_value_t = 4,
+            help = "How many files to download at the same time."
+        )]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[arg(
+            long,
+            value_parser = parse_rate,
+            help = "The most bytes per second all files can download at together, e.g. 10M. Type a new rate and press Enter while downloading to change it."
+        )]
+        total_limit_rate: Option<u64>,
+        #[arg(
+            long,
+            help = "Only accept files with the SHA-256 recorded in download_it.lock by the `lock` command."
+        )]
+        locked: bool,
+        /// The manifest, a .toml file or a .yaml or .yml file.
+        manifest: String,
+    },
+}
+
+/// How each file is transferred and how progress is shown, shared by every command that
+/// downloads.
+#[derive(Debug, Args)]
+pub struct TransferArgs {
+    #[arg(
+        long,
+        value_parser = parse_rate,
+        help = "The most bytes per second each file can download at, e.g. 500K or 2M."
+    )]
+    limit_rate: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "How many times to retry a download after a timeout, dropped connection or server error."
+    )]
+    retries: u32,
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Seconds to wait before the first retry, doubled for each retry after it."
+    )]
+    retry_delay: u64,
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "The most seconds to wait between retries."
+    )]
+    max_retry_delay: u64,
+    #[arg(
+        long,
+        help = "The most seconds to wait for a connection to the server."
+    )]
+    connect_timeout: Option<u64>,
+    #[arg(
+        long,
+        help = "The most seconds a transfer can take before it's stopped and retried."
+    )]
+    timeout: Option<u64>,
+    #[arg(
+        long,
+        value_parser = parse_rate,
+        default_value = "1",
+        help = "A transfer slower than this many bytes per second for --stall-time is stalled, and is stopped and resumed, e.g. 10K."
+    )]
+    stall_speed: u64,
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "How many seconds a transfer can stay under --stall-speed before it's stalled. 0 never gives up on a slow transfer."
+    )]
+    stall_time: u64,
+    #[arg(
+        long,
+        default_value_t = Output::Human,
+        help = "How to show progress: human for progress bars, or plain lines when stderr isn't a terminal, or json for one JSON event per line on standard output."
+    )]
+    output: Output,
+}
+
+impl From<&TransferArgs> for DownloadOptions {
+    fn from(transfer: &TransferArgs) -> Self {
+        Self {
+            limit_rate: transfer.limit_rate,
+            retries: transfer.retries,
+            retry_delay: Duration::from_secs(transfer.retry_delay),
+            max_retry_delay: Duration::from_secs(transfer.max_retry_delay),
+            connect_timeout: transfer.connect_timeout.map(Duration::from_secs),
+            timeout: transfer.timeout.map(Duration::from_secs),
+            stall_speed: transfer.stall_speed,
+            stall_time: Duration::from_secs(transfer.stall_time),
+            output: transfer.output,
+            multi_progress: transfer.output.multi_progress(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parses a rate like `500K`, `1.5M` or `1G` into bytes per second. `0` means no limit.
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    parse_bytes(value).ok_or_else(|| {
+        format!(
+            "`{}` is not a rate, expected bytes per second like 500K or 2M",
+            value.trim()
+        )
+    })
+}
+
+/// Parses a size like `700M` or `4.5G` into bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse_bytes(value).ok_or_else(|| {
+        format!(
+            "`{}` is not a size, expected bytes like 700M or 2G",
+            value.trim()
+        )
+    })
+}
+
+/// Reads a number of bytes with an optional `K`, `M` or `G` suffix.
+fn parse_bytes(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024.0),
+        Some('M') => (&value[..value.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&value[..value.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (value, 1.0),
+    };
+
+    match number.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => Some((number * multiplier) as u64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bytes_with_a_suffix() {
+        assert_eq!(parse_bytes("0"), Some(0));
+        assert_eq!(parse_bytes("1500"), Some(1500));
+        assert_eq!(parse_bytes("500K"), Some(500 * 1024));
+        assert_eq!(parse_bytes("500k"), Some(500 * 1024));
+        assert_eq!(parse_bytes("1.5M"), Some(1024 * 1024 * 3 / 2));
+        assert_eq!(parse_bytes(" 2 G "), Some(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn refuses_what_is_not_a_number_of_bytes() {
+        for value in [
+            "", " ", "K", "-1", "-1K", "abc", "5X", "1.2.3M", "inf", "NaN",
+        ] {
+            assert_eq!(parse_bytes(value), None, "{value:?}");
+        }
+        assert!(parse_rate("fast").unwrap_err().contains("not a rate"));
+        assert!(parse_size("big").unwrap_err().contains("not a size"));
+    }
 }
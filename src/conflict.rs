@@ -0,0 +1,238 @@
+use crate::naming::{numbered_file_name, part_file_name};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What to do when the file a download would be saved as is already there.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    Overwrite,
+    /// Leave the existing file alone and don't download anything.
+    Skip,
+    /// Save to the first free name like `file (1).iso`.
+    #[default]
+    Rename,
+    /// Treat the existing file as the start of the download and fetch the rest.
+    Resume,
+    /// Stop with an error.
+    Fail,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "rename" => Ok(Self::Rename),
+            "resume" => Ok(Self::Resume),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!(
+                "Unknown conflict mode `{value}`, expected overwrite, skip, rename, resume or fail"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Overwrite => "overwrite",
+            Self::Skip => "skip",
+            Self::Rename => "rename",
+            Self::Resume => "resume",
+            Self::Fail => "fail",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// How a download goes on once its file name has been checked for conflicts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// Start a new file with this name.
+    Create(String),
+    /// Carry on from the end of the file that's there.
+    Resume,
+    /// Don't download it.
+    Skip,
+}
+
+/// The paths downloads in this run are saving to, so two of them never write the same file.
+#[derive(Debug, Default)]
+pub struct Claims(Mutex<HashSet<PathBuf>>);
+
+impl Claims {
+    /// Works out where a download named `file_name` in `dir` should go under `policy`, and
+    /// claims that path for it. When `resuming`, the file on disk is the download's own
+    /// partial file, so only another download in the run saving to it is a conflict.
+    pub fn resolve(
+        &self,
+        policy: ConflictPolicy,
+        dir: &str,
+        file_name: &str,
+        resuming: bool,
+    ) -> Result<Resolution, String> {
+        let mut claimed = self.0.lock().unwrap();
+        let dir = Path::new(dir);
+        let path = dir.join(file_name);
+        let taken = claimed.contains(&path);
+        // An interrupted download leaves only its `.part` file, which is what there is to resume
+        let exists = path.exists()
+            || (policy == ConflictPolicy::Resume && dir.join(part_file_name(file_name)).exists());
+
+        if !taken && (resuming || !exists) {
+            claimed.insert(path);
+            return Ok(if resuming {
+                Resolution::Resume
+            } else {
+                Resolution::Create(file_name.to_string())
+            });
+        }
+
+        match policy {
+            ConflictPolicy::Skip => Ok(Resolution::Skip),
+            ConflictPolicy::Rename => {
+                let file_name = (1..)
+                    .map(|number| numbered_file_name(file_name, number))
+                    .find(|file_name| {
+                        let path = dir.join(file_name);
+                        !claimed.contains(&path) && !path.exists()
+                    })
+                    .unwrap_or_default();
+                claimed.insert(dir.join(&file_name));
+
+                Ok(Resolution::Create(file_name))
+            }
+            _ if taken => Err(format!(
+                "Another download in this run is already saving to {}",
+                path.display()
+            )),
+            ConflictPolicy::Overwrite => {
+                claimed.insert(path);
+                Ok(Resolution::Create(file_name.to_string()))
+            }
+            ConflictPolicy::Resume => {
+                claimed.insert(path);
+                Ok(Resolution::Resume)
+            }
+            ConflictPolicy::Fail => Err(format!(
+                "{} already exists, use --on-conflict to overwrite, skip, rename or resume it",
+                path.display()
+            )),
+        }
+    }
+
+    /// Lets other downloads use `path` again.
+    pub fn release(&self, path: &Path) {
+        self.0.lock().unwrap().remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    /// An empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("download_it-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn claims_a_free_path_once() {
+        let dir = test_dir("free");
+        let dir = dir.to_str().unwrap();
+        let claims = Claims::default();
+
+        assert_eq!(
+            claims.resolve(ConflictPolicy::Fail, dir, "a.iso", false),
+            Ok(Resolution::Create("a.iso".to_string()))
+        );
+        // Another download in the run can't have it, even to overwrite or resume it
+        for policy in [
+            ConflictPolicy::Fail,
+            ConflictPolicy::Overwrite,
+            ConflictPolicy::Resume,
+        ] {
+            assert!(claims.resolve(policy, dir, "a.iso", false).is_err());
+        }
+        assert_eq!(
+            claims.resolve(ConflictPolicy::Rename, dir, "a.iso", false),
+            Ok(Resolution::Create("a (1).iso".to_string()))
+        );
+
+        claims.release(&Path::new(dir).join("a.iso"));
+        assert_eq!(
+            claims.resolve(ConflictPolicy::Fail, dir, "a.iso", false),
+            Ok(Resolution::Create("a.iso".to_string()))
+        );
+    }
+
+    #[test]
+    fn follows_the_policy_for_a_file_on_disk() {
+        let dir = test_dir("existing");
+        fs::write(dir.join("a.tar.gz"), "old").unwrap();
+        fs::write(dir.join("a (1).tar.gz"), "older").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let resolve = |policy| Claims::default().resolve(policy, dir, "a.tar.gz", false);
+        assert_eq!(resolve(ConflictPolicy::Skip), Ok(Resolution::Skip));
+        assert_eq!(
+            resolve(ConflictPolicy::Overwrite),
+            Ok(Resolution::Create("a.tar.gz".to_string()))
+        );
+        assert_eq!(resolve(ConflictPolicy::Resume), Ok(Resolution::Resume));
+        assert_eq!(
+            resolve(ConflictPolicy::Rename),
+            Ok(Resolution::Create("a (2).tar.gz".to_string()))
+        );
+        let error = resolve(ConflictPolicy::Fail).unwrap_err();
+        assert!(error.contains("already exists"), "{error}");
+    }
+
+    #[test]
+    fn resumes_a_part_file_left_by_an_interrupted_download() {
+        let dir = test_dir("part");
+        fs::write(dir.join("a.iso.part"), "partial").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let resolve = |policy| Claims::default().resolve(policy, dir, "a.iso", false);
+        assert_eq!(resolve(ConflictPolicy::Resume), Ok(Resolution::Resume));
+        // The other policies only care about the file under its final name
+        assert_eq!(
+            resolve(ConflictPolicy::Fail),
+            Ok(Resolution::Create("a.iso".to_string()))
+        );
+        assert_eq!(
+            resolve(ConflictPolicy::Rename),
+            Ok(Resolution::Create("a.iso".to_string()))
+        );
+    }
+
+    #[test]
+    fn resumes_its_own_partial_file() {
+        let dir = test_dir("resuming");
+        fs::write(dir.join("a.iso"), "partial").unwrap();
+        let dir = dir.to_str().unwrap();
+        let claims = Claims::default();
+
+        assert_eq!(
+            claims.resolve(ConflictPolicy::Fail, dir, "a.iso", true),
+            Ok(Resolution::Resume)
+        );
+        assert!(
+            claims
+                .resolve(ConflictPolicy::Fail, dir, "a.iso", true)
+                .is_err()
+        );
+    }
+}
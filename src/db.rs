@@ -1,11 +1,49 @@
-use crate::download::{Download, DownloadStatus};
-use indicatif::ProgressBar;
-use rusqlite::Connection;
-use std::{
-    error::Error,
-    io::{Error as IoError, ErrorKind as IoErrorKind},
-    sync::Arc,
+use crate::{
+    checksum::Checksum,
+    download::{Download, DownloadStatus, default_progress_bar},
+    naming::{DEFAULT_FILE_NAME, sanitize_file_name},
+    transfer::Segment,
 };
+use rusqlite::{Connection, Row, params};
+use std::{error::Error, io::Error as IoError, path::Path};
+
+/// Downloads are saved by their URL and the path they're saved to, so the same URL saved to
+/// two places is resumed as two downloads.
+const RESUMES_TABLE: &str = "CREATE TABLE IF NOT EXISTS resumes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    target TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT,
+    downloaded INTEGER NOT NULL DEFAULT 0,
+    etag TEXT,
+    last_modified TEXT,
+    checksum TEXT,
+    headers TEXT,
+    cookie TEXT,
+    mirrors TEXT,
+    size INTEGER,
+    pieces TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (url, target)
+)";
+
+const SEGMENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS segments (
+    url TEXT NOT NULL,
+    target TEXT NOT NULL,
+    idx INTEGER NOT NULL,
+    start_byte INTEGER NOT NULL,
+    end_byte INTEGER NOT NULL,
+    downloaded INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (url, target, idx)
+)";
+
+/// The columns `download_from_row` reads, in order.
+const RESUME_COLUMNS: &str = "url, file_name, file_path, status, error, downloaded, etag,
+    last_modified, checksum, headers, cookie, mirrors, size, pieces";
 
 #[derive(Debug)]
 pub struct ResumeDb {
@@ -21,11 +59,10 @@ impl ResumeDb {
 
         if !db_path.exists() {
             match std::fs::create_dir_all(&db_path) {
-                Ok(_) => println!("Database path created successfully!"),
+                Ok(_) => eprintln!("Database path created successfully!"),
                 Err(e) => {
                     eprintln!("Resume database path failed to be created: {e}");
-                    return Err(Box::new(IoError::new(
-                        IoErrorKind::Other,
+                    return Err(Box::new(IoError::other(
                         "Could not create the resume database path in config directory.",
                     )));
                 }
@@ -39,79 +76,110 @@ impl ResumeDb {
             Err(e) => return Err(Box::new(e)),
         };
 
+        Self::init(conn)
+    }
+
+    /// A database that only lasts as long as the test using it.
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, Box<dyn Error>> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    /// Creates the tables, or brings ones made by an older version up to date.
+    fn init(mut conn: Connection) -> Result<Self, Box<dyn Error>> {
         // Create the table if it doesn't exist
+        conn.execute(RESUMES_TABLE, [])?;
+
+        // Byte ranges of segmented downloads, so each one can be resumed where it stopped
+        conn.execute(SEGMENTS_TABLE, [])?;
+
+        // The files `sync` downloaded for each manifest, the only ones `--prune` removes
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS resumes (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                url TEXT NOT NULL UNIQUE,
-                file_name TEXT NOT NULL,
-                file_path TEXT NOT NULL,
-                status TEXT NOT NULL,
-                error TEXT,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            "CREATE TABLE IF NOT EXISTS synced_files (
+                manifest TEXT NOT NULL,
+                path TEXT NOT NULL,
+                PRIMARY KEY (manifest, path)
             )",
             [],
         )?;
 
+        // Bring databases created by older versions up to date
+        add_column_if_missing(&conn, "downloaded", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "etag", "TEXT")?;
+        add_column_if_missing(&conn, "last_modified", "TEXT")?;
+        add_column_if_missing(&conn, "checksum", "TEXT")?;
+        add_column_if_missing(&conn, "headers", "TEXT")?;
+        add_column_if_missing(&conn, "cookie", "TEXT")?;
+        add_column_if_missing(&conn, "mirrors", "TEXT")?;
+        add_column_if_missing(&conn, "size", "INTEGER")?;
+        add_column_if_missing(&conn, "pieces", "TEXT")?;
+        key_by_target(&mut conn)?;
+
         Ok(Self { conn })
     }
 
+    /// Saves a download that is starting, replacing any earlier entry for the same URL and
+    /// path.
     pub fn create_resume(&self, download: &Download) -> Result<(), Box<dyn Error>> {
         let status = serde_json::to_string(&download.status)?;
-        let err = if let Some(err) = download.error.clone() {
-            err
-        } else {
-            String::new()
-        };
+        let err = download.error.clone().unwrap_or_default();
+        let checksum = download
+            .checksum
+            .as_ref()
+            .map(|checksum| checksum.to_string());
+        let headers = serde_json::to_string(&download.headers)?;
+        let mirrors = serde_json::to_string(&download.mirrors)?;
+        let pieces = download
+            .pieces
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
         self.conn.execute(
-            "INSERT INTO resumes (url, file_name, file_path, status, error)
-            VALUES (?1, ?2, ?3, ?4, ?5)",
-            [
+            "INSERT INTO resumes (url, file_name, file_path, status, error, downloaded, etag, last_modified, checksum, headers, cookie, mirrors, size, pieces, target)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+            ON CONFLICT(url, target) DO UPDATE SET
+                file_name = excluded.file_name,
+                file_path = excluded.file_path,
+                status = excluded.status,
+                error = excluded.error,
+                downloaded = excluded.downloaded,
+                etag = excluded.etag,
+                last_modified = excluded.last_modified,
+                checksum = excluded.checksum,
+                headers = excluded.headers,
+                cookie = excluded.cookie,
+                mirrors = excluded.mirrors,
+                size = excluded.size,
+                pieces = excluded.pieces,
+                updated_at = CURRENT_TIMESTAMP",
+            params![
                 &download.url,
                 &download.file_name,
                 &download.file_path,
                 &status,
                 &err,
+                download.downloaded,
+                &download.etag,
+                &download.last_modified,
+                &checksum,
+                &headers,
+                &download.cookie,
+                &mirrors,
+                download.size,
+                &pieces,
+                target_key(&download.target_path()),
             ],
         )?;
 
         Ok(())
     }
 
-    pub fn get_resume(&self, url: &str) -> Result<Option<Download>, Box<dyn Error>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT url, file_name, file_path, status, error FROM resumes WHERE url = ?1",
-        )?;
-
-        let mut rows = stmt.query_map([url], |row| {
-            let status_json: String = row.get(3)?;
-            let status: DownloadStatus = serde_json::from_str(&status_json).map_err(|err| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    3,
-                    rusqlite::types::Type::Text,
-                    Box::new(err),
-                )
-            })?;
-
-            let error_str: String = row.get(4)?;
-            let error = if error_str.is_empty() {
-                None
-            } else {
-                Some(error_str)
-            };
-
-            let progress_bar = Arc::new(ProgressBar::new(0));
-
-            Ok(Download {
-                url: row.get(0)?,
-                file_name: row.get(1)?,
-                file_path: row.get(2)?,
-                progress_bar,
-                status,
-                error,
-            })
-        })?;
+    /// The download of `url` saved to `target`.
+    pub fn get_resume(&self, url: &str, target: &Path) -> Result<Option<Download>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {RESUME_COLUMNS} FROM resumes WHERE url = ?1 AND target = ?2"
+        ))?;
+        let mut rows = stmt.query_map(params![url, target_key(target)], download_from_row)?;
 
         match rows.next() {
             Some(row) => Ok(Some(row?)),
@@ -119,34 +187,495 @@ impl ResumeDb {
         }
     }
 
+    /// Every download of `url`, wherever it was saved to, the most recent first.
+    pub fn get_resumes(&self, url: &str) -> Result<Vec<Download>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {RESUME_COLUMNS} FROM resumes WHERE url = ?1
+             ORDER BY updated_at DESC, id DESC"
+        ))?;
+        let downloads = stmt
+            .query_map([url], download_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(downloads)
+    }
+
     pub fn delete_resume(&self, download: &Download) -> Result<(), Box<dyn Error>> {
-        self.conn
-            .execute("DELETE FROM resumes WHERE url = ?1", [&download.url])?;
+        self.conn.execute(
+            "DELETE FROM resumes WHERE url = ?1 AND target = ?2",
+            params![&download.url, target_key(&download.target_path())],
+        )?;
 
         Ok(())
     }
 
     pub fn update_resume(&self, download: &Download) -> Result<(), Box<dyn Error>> {
         let status = serde_json::to_string(&download.status)?;
-        let err = if let Some(err) = download.error.clone() {
-            err
-        } else {
-            String::new()
-        };
+        let err = download.error.clone().unwrap_or_default();
 
         self.conn.execute(
             "UPDATE resumes
-             SET file_name = ?1, file_path = ?2, status = ?3, error = ?4, updated_at = CURRENT_TIMESTAMP
-             WHERE url = ?5",
-             [
-                 &download.file_name,
-                 &download.file_path,
-                 &status,
-                 &err,
-                 &download.url,
-             ]
+             SET file_name = ?1, file_path = ?2, status = ?3, error = ?4, downloaded = ?5,
+                 etag = ?6, last_modified = ?7, updated_at = CURRENT_TIMESTAMP
+             WHERE url = ?8 AND target = ?9",
+            params![
+                &download.file_name,
+                &download.file_path,
+                &status,
+                &err,
+                download.downloaded,
+                &download.etag,
+                &download.last_modified,
+                &download.url,
+                target_key(&download.target_path()),
+            ],
         )?;
 
         Ok(())
     }
+
+    /// Records how many bytes of a running download have been written to disk.
+    pub fn update_progress(
+        &self,
+        url: &str,
+        target: &Path,
+        downloaded: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE resumes SET downloaded = ?1, updated_at = CURRENT_TIMESTAMP
+             WHERE url = ?2 AND target = ?3",
+            params![downloaded, url, target_key(target)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Saves the name a download going to `target` was given once its response came in. It
+    /// takes the place of anything saved about the URL under that name before.
+    pub fn update_file_name(
+        &self,
+        url: &str,
+        target: &Path,
+        file_name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let renamed = target.with_file_name(file_name);
+        if renamed == target {
+            return Ok(());
+        }
+
+        self.conn.execute(
+            "DELETE FROM resumes WHERE url = ?1 AND target = ?2",
+            params![url, target_key(&renamed)],
+        )?;
+        self.conn.execute(
+            "UPDATE resumes SET file_name = ?1, target = ?2, updated_at = CURRENT_TIMESTAMP
+             WHERE url = ?3 AND target = ?4",
+            params![file_name, target_key(&renamed), url, target_key(target)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Saves the ETag and Last-Modified date the server sent for a download.
+    pub fn update_validators(
+        &self,
+        url: &str,
+        target: &Path,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE resumes SET etag = ?1, last_modified = ?2, updated_at = CURRENT_TIMESTAMP
+             WHERE url = ?3 AND target = ?4",
+            params![etag, last_modified, url, target_key(target)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Replaces the saved segments of a download with `segments`.
+    pub fn create_segments(
+        &self,
+        url: &str,
+        target: &Path,
+        segments: &[Segment],
+    ) -> Result<(), Box<dyn Error>> {
+        self.delete_segments(url, target)?;
+
+        for segment in segments {
+            self.conn.execute(
+                "INSERT INTO segments (url, target, idx, start_byte, end_byte, downloaded)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    url,
+                    target_key(target),
+                    segment.index,
+                    segment.start,
+                    segment.end,
+                    segment.downloaded
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the saved segments of a download, empty if it wasn't a segmented download.
+    pub fn get_segments(&self, url: &str, target: &Path) -> Result<Vec<Segment>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT idx, start_byte, end_byte, downloaded FROM segments
+             WHERE url = ?1 AND target = ?2 ORDER BY idx",
+        )?;
+
+        let segments = stmt
+            .query_map(params![url, target_key(target)], |row| {
+                Ok(Segment {
+                    index: row.get(0)?,
+                    start: row.get(1)?,
+                    end: row.get(2)?,
+                    downloaded: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(segments)
+    }
+
+    pub fn update_segment(
+        &self,
+        url: &str,
+        target: &Path,
+        segment: &Segment,
+    ) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE segments SET downloaded = ?1 WHERE url = ?2 AND target = ?3 AND idx = ?4",
+            params![segment.downloaded, url, target_key(target), segment.index],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_segments(&self, url: &str, target: &Path) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "DELETE FROM segments WHERE url = ?1 AND target = ?2",
+            params![url, target_key(target)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records that `path` was downloaded for the manifest at `manifest`.
+    pub fn create_synced_file(&self, manifest: &str, path: &str) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO synced_files (manifest, path) VALUES (?1, ?2)",
+            params![manifest, path],
+        )?;
+
+        Ok(())
+    }
+
+    /// Gets the files downloaded for the manifest at `manifest`.
+    pub fn get_synced_files(&self, manifest: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path FROM synced_files WHERE manifest = ?1 ORDER BY path")?;
+
+        let paths = stmt
+            .query_map([manifest], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(paths)
+    }
+
+    pub fn delete_synced_file(&self, manifest: &str, path: &str) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "DELETE FROM synced_files WHERE manifest = ?1 AND path = ?2",
+            params![manifest, path],
+        )?;
+
+        Ok(())
+    }
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, Box<dyn Error>> {
+    let exists = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )?;
+
+    Ok(exists)
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    column: &str,
+    definition: &str,
+) -> Result<(), Box<dyn Error>> {
+    if !has_column(conn, "resumes", column)? {
+        conn.execute(
+            &format!("ALTER TABLE resumes ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Older versions saved one download per URL, so saving it to a second place took over the
+/// first one's progress and segments. Rebuilds both tables keyed on the URL and the path.
+fn key_by_target(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    if has_column(conn, "resumes", "target")? {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        "ALTER TABLE resumes RENAME TO old_resumes;
+         ALTER TABLE segments RENAME TO old_segments;",
+    )?;
+    tx.execute(RESUMES_TABLE, [])?;
+    tx.execute(SEGMENTS_TABLE, [])?;
+
+    let saved = tx
+        .prepare("SELECT id, file_path, file_name FROM old_resumes")?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (id, file_path, file_name) in saved {
+        // The path the download is read back with, names included
+        let file_name =
+            sanitize_file_name(&file_name).unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
+        tx.execute(
+            &format!(
+                "INSERT INTO resumes (id, target, {RESUME_COLUMNS}, created_at, updated_at)
+                 SELECT id, ?2, {RESUME_COLUMNS}, created_at, updated_at
+                 FROM old_resumes WHERE id = ?1"
+            ),
+            params![id, target_key(&Path::new(&file_path).join(file_name))],
+        )?;
+    }
+    // URLs were unique before, so each segment goes with the one download of its URL
+    tx.execute_batch(
+        "INSERT INTO segments (url, target, idx, start_byte, end_byte, downloaded)
+         SELECT old_segments.url, resumes.target, idx, start_byte, end_byte,
+                old_segments.downloaded
+         FROM old_segments JOIN resumes ON resumes.url = old_segments.url;
+         DROP TABLE old_resumes;
+         DROP TABLE old_segments;",
+    )?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// How `target` is saved in the database.
+fn target_key(target: &Path) -> String {
+    target.to_string_lossy().to_string()
+}
+
+/// Reads a column holding a JSON list of strings, like a download's headers.
+fn string_list(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Vec<String>> {
+    row.get::<_, Option<String>>(idx)?
+        .map(|list| serde_json::from_str::<Vec<String>>(&list))
+        .transpose()
+        .map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(
+                idx,
+                rusqlite::types::Type::Text,
+                Box::new(err),
+            )
+        })
+        .map(Option::unwrap_or_default)
+}
+
+/// Reads a download saved in the `resumes` table, selected as `RESUME_COLUMNS`.
+fn download_from_row(row: &Row) -> rusqlite::Result<Download> {
+    let status_json: String = row.get(3)?;
+    let status: DownloadStatus = serde_json::from_str(&status_json).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(err))
+    })?;
+
+    let error_str: String = row.get(4)?;
+    let error = if error_str.is_empty() {
+        None
+    } else {
+        Some(error_str)
+    };
+
+    let checksum = row
+        .get::<_, Option<String>>(8)?
+        .map(|checksum| checksum.parse::<Checksum>())
+        .transpose()
+        .map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Text, err.into())
+        })?;
+
+    let headers = string_list(row, 9)?;
+    let mirrors = string_list(row, 11)?;
+    let pieces = row
+        .get::<_, Option<String>>(13)?
+        .map(|pieces| serde_json::from_str(&pieces))
+        .transpose()
+        .map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(
+                13,
+                rusqlite::types::Type::Text,
+                Box::new(err),
+            )
+        })?;
+
+    // Older versions saved names as they came, so don't trust them to stay put
+    let file_name = sanitize_file_name(&row.get::<_, String>(1)?)
+        .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
+
+    let progress_bar = default_progress_bar();
+
+    Ok(Download {
+        url: row.get(0)?,
+        mirrors,
+        file_name,
+        file_path: row.get(2)?,
+        progress_bar,
+        status,
+        error,
+        downloaded: row.get(5)?,
+        etag: row.get(6)?,
+        last_modified: row.get(7)?,
+        checksum,
+        headers,
+        cookie: row.get(10)?,
+        size: row.get(12)?,
+        pieces,
+        name_from_response: false,
+        started: None,
+        duration: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/debian.iso";
+
+    fn download(dir: &str, file_name: &str) -> Download {
+        Download::new(
+            URL.to_string(),
+            Some(file_name.to_string()),
+            Some(dir.to_string()),
+            None,
+        )
+    }
+
+    fn segment(index: usize, downloaded: u64) -> Segment {
+        Segment {
+            index,
+            start: index as u64 * 100,
+            end: index as u64 * 100 + 99,
+            downloaded,
+        }
+    }
+
+    #[test]
+    fn keeps_a_url_saved_to_two_places_apart() {
+        let db = ResumeDb::in_memory().unwrap();
+        let first = download("/srv/a", "debian.iso");
+        let second = download("/srv/b", "debian.iso");
+        db.create_resume(&first).unwrap();
+        db.create_resume(&second).unwrap();
+
+        db.update_progress(URL, &first.target_path(), 100).unwrap();
+        db.update_progress(URL, &second.target_path(), 200).unwrap();
+        db.create_segments(URL, &first.target_path(), &[segment(0, 10)])
+            .unwrap();
+        db.create_segments(URL, &second.target_path(), &[segment(0, 20), segment(1, 0)])
+            .unwrap();
+
+        let saved = db.get_resume(URL, &first.target_path()).unwrap().unwrap();
+        assert_eq!(saved.downloaded, 100);
+        let saved = db.get_resume(URL, &second.target_path()).unwrap().unwrap();
+        assert_eq!(saved.downloaded, 200);
+        assert_eq!(
+            db.get_segments(URL, &first.target_path()).unwrap(),
+            [segment(0, 10)]
+        );
+        assert_eq!(
+            db.get_segments(URL, &second.target_path()).unwrap().len(),
+            2
+        );
+        assert_eq!(db.get_resumes(URL).unwrap().len(), 2);
+
+        db.delete_segments(URL, &first.target_path()).unwrap();
+        assert!(
+            db.get_segments(URL, &first.target_path())
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            db.get_segments(URL, &second.target_path()).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn moves_a_download_to_the_name_the_response_gave_it() {
+        let db = ResumeDb::in_memory().unwrap();
+        let placeholder = download("/srv", "download");
+        let named = download("/srv", "debian.iso");
+        db.create_resume(&named).unwrap();
+        db.create_resume(&placeholder).unwrap();
+
+        db.update_file_name(URL, &placeholder.target_path(), "debian.iso")
+            .unwrap();
+
+        let saved = db.get_resumes(URL).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].target_path(), named.target_path());
+    }
+
+    #[test]
+    fn keys_downloads_saved_by_older_versions_by_their_path() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE resumes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL UNIQUE,
+                file_name TEXT NOT NULL,
+                file_path TEXT NOT NULL,
+                status TEXT NOT NULL,
+                error TEXT,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE segments (
+                url TEXT NOT NULL,
+                idx INTEGER NOT NULL,
+                start_byte INTEGER NOT NULL,
+                end_byte INTEGER NOT NULL,
+                downloaded INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (url, idx)
+            );
+            INSERT INTO resumes (url, file_name, file_path, status, error)
+            VALUES ('https://example.com/debian.iso', 'debian.iso', '/srv', '\"Failed\"', '');
+            INSERT INTO segments (url, idx, start_byte, end_byte, downloaded)
+            VALUES ('https://example.com/debian.iso', 0, 0, 99, 50);",
+        )
+        .unwrap();
+
+        let db = ResumeDb::init(conn).unwrap();
+        let target = download("/srv", "debian.iso").target_path();
+        let saved = db.get_resume(URL, &target).unwrap().unwrap();
+        assert_eq!(saved.status, DownloadStatus::Failed);
+        assert_eq!(db.get_segments(URL, &target).unwrap(), [segment(0, 50)]);
+
+        // The same URL can now be saved somewhere else too
+        db.create_resume(&download("/srv/b", "debian.iso")).unwrap();
+        assert_eq!(db.get_resumes(URL).unwrap().len(), 2);
+    }
 }
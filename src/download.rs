@@ -1,29 +1,166 @@
-use curl::easy::Easy;
+use crate::{
+    checksum::{Checksum, ChecksumList, Pieces, digest_file},
+    conflict::{Claims, ConflictPolicy, Resolution},
+    db::ResumeDb,
+    events::{Event, Output},
+    naming::{
+        DEFAULT_FILE_NAME, file_name_from_response, file_name_from_url, part_file_name,
+        sanitize_file_name,
+    },
+    transfer::{
+        ContentRange, Failure, MIN_SEGMENT_SIZE, RateLimiter, Segment, Share, TransferHandler,
+        new_easy,
+    },
+};
+use curl::{
+    easy::Easy2,
+    multi::{Easy2Handle, Multi},
+};
 use dirs::download_dir;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use fs4::FileExt;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io::{Error as IoError, ErrorKind as IoErrorKind, Write};
-use std::path::Path;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// The longest the event loop sleeps waiting for network activity.
+const WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How often to check if transfers paused by the rate limiter can go on.
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How much of the file to fetch from each mirror to find the fastest one.
+const MIRROR_SAMPLE_SIZE: u64 = 256 * 1024;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Download {
     pub url: String,
+    /// Other URLs with the same file, which the download can switch to at any point.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
     pub file_name: String,
     pub file_path: String,
     #[serde(skip, default = "default_progress_bar")]
     pub progress_bar: Arc<ProgressBar>,
     pub status: DownloadStatus,
     pub error: Option<String>,
+    #[serde(default)]
+    pub downloaded: u64,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// The digest the finished file has to match.
+    #[serde(default)]
+    pub checksum: Option<Checksum>,
+    /// Headers sent for this file on top of the ones every file gets.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// A cookie file sent for this file instead of the one every file gets.
+    #[serde(default)]
+    pub cookie: Option<String>,
+    /// How big the finished file has to be, when that's known up front.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Digests of the file's pieces, so only the damaged ones are downloaded again.
+    #[serde(default)]
+    pub pieces: Option<Pieces>,
+    /// Whether the file should be named after the response instead of the URL it was
+    /// asked for, because no name was given.
+    #[serde(skip)]
+    pub name_from_response: bool,
+    /// When this run started the transfer and how many bytes were already there, for the
+    /// summary at the end.
+    #[serde(skip)]
+    pub started: Option<(Instant, u64)>,
+    /// How long the download took in this run, once it's done.
+    #[serde(skip)]
+    pub duration: Option<Duration>,
 }
 
-fn default_progress_bar() -> Arc<ProgressBar> {
-    let progress_bar = Arc::new(ProgressBar::new(0));
+/// The settings that apply to every file in a `single`, `multi` or `resume` run.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// A cookie file to send with each request.
+    pub cookie: Option<String>,
+    /// Extra headers to send with each request.
+    pub headers: Vec<String>,
+    /// How many byte ranges to split each file into.
+    pub segments: usize,
+    /// How many files to download at the same time.
+    pub jobs: usize,
+    /// Where to look up the checksum of files that weren't given one.
+    pub checksum_list: Option<ChecksumList>,
+    /// The most bytes per second each file can download at.
+    pub limit_rate: Option<u64>,
+    /// Caps how fast all the files download together.
+    pub total_rate_limiter: Arc<RateLimiter>,
+    /// How many times to retry a download that failed for a reason that may pass.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubled for each one after it.
+    pub retry_delay: Duration,
+    /// The longest to wait between retries.
+    pub max_retry_delay: Duration,
+    /// How long to wait for a connection to the server.
+    pub connect_timeout: Option<Duration>,
+    /// The longest a transfer can take.
+    pub timeout: Option<Duration>,
+    /// A transfer getting less than this many bytes per second for `stall_time` has stalled,
+    /// and is stopped to be retried. It's never stopped when `stall_time` is zero.
+    pub stall_speed: u64,
+    pub stall_time: Duration,
+    /// What to do when a file is already there.
+    pub on_conflict: ConflictPolicy,
+    /// The biggest file to download, in bytes.
+    pub max_size: Option<u64>,
+    /// How progress and what happens to each file are shown.
+    pub output: Output,
+    /// The progress bars of the run, drawn as `output` says.
+    pub multi_progress: MultiProgress,
+}
 
-    progress_bar
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            cookie: None,
+            headers: Vec::new(),
+            segments: 1,
+            jobs: 4,
+            checksum_list: None,
+            limit_rate: None,
+            total_rate_limiter: Arc::new(RateLimiter::default()),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            max_retry_delay: Duration::from_secs(60),
+            connect_timeout: None,
+            timeout: None,
+            stall_speed: 1,
+            stall_time: Duration::from_secs(30),
+            on_conflict: ConflictPolicy::default(),
+            max_size: None,
+            output: Output::default(),
+            multi_progress: Output::default().multi_progress(),
+        }
+    }
+}
+
+pub fn default_progress_bar() -> Arc<ProgressBar> {
+    let progress_bar = ProgressBar::new(0);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg}")
+            .expect("Could not create a default ProgressBar template")
+            .progress_chars("#>-"),
+    );
+
+    Arc::new(progress_bar)
 }
 
 impl Download {
@@ -33,16 +170,13 @@ impl Download {
         file_path: Option<String>,
         multi_progress: Option<&MultiProgress>,
     ) -> Self {
-        let file_name = if let Some(file_name) = file_name {
-            file_name
-        } else {
-            let file_name = url.clone();
-            let file_name = file_name
-                .split("/")
-                .last()
-                .expect("Should have gotten file name");
-            file_name.to_string()
-        };
+        // Without a name, go by the URL until the response has a better one
+        let name_from_response = file_name.is_none();
+        let file_name = match file_name {
+            Some(file_name) => sanitize_file_name(&file_name),
+            None => file_name_from_url(&url),
+        }
+        .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
 
         let file_path = if let Some(file_path) = file_path {
             file_path
@@ -55,394 +189,1767 @@ impl Download {
             downloads.clone()
         };
 
-        let progress_bar = ProgressBar::new(0);
-        progress_bar.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .expect("Could not create a default ProgressBar template")
-            .progress_chars("#>-")
-        );
-
-        let progress_bar = if let Some(multi_progress) = multi_progress {
-            Arc::new(multi_progress.add(progress_bar))
-        } else {
-            Arc::new(progress_bar)
-        };
-
-        Self {
+        let mut download = Self {
             url,
+            mirrors: Vec::new(),
             file_name,
             file_path,
-            progress_bar,
+            progress_bar: default_progress_bar(),
             status: DownloadStatus::Pending,
             error: None,
+            downloaded: 0,
+            etag: None,
+            last_modified: None,
+            checksum: None,
+            headers: Vec::new(),
+            cookie: None,
+            size: None,
+            pieces: None,
+            name_from_response,
+            started: None,
+            duration: None,
+        };
+
+        if let Some(multi_progress) = multi_progress {
+            download.attach_progress_bar(multi_progress);
         }
+
+        download
+    }
+
+    /// Adds this download's progress bar to a `MultiProgress` so it renders alongside the others.
+    pub fn attach_progress_bar(&mut self, multi_progress: &MultiProgress) {
+        self.progress_bar = Arc::new(multi_progress.add((*self.progress_bar).clone()));
     }
 
     pub fn execute(
         &mut self,
-        cookie: Option<String>,
-        headers: Option<Vec<String>>,
+        options: &DownloadOptions,
+        db: Arc<Mutex<ResumeDb>>,
     ) -> Result<(), Box<dyn Error>> {
-        // Create the download file path
-        let file_path = Path::new(&self.file_path);
-        if !file_path.exists() {
-            std::fs::create_dir_all(file_path)?;
+        self.run(options, db, false)
+    }
+
+    pub fn execute_resume(
+        &mut self,
+        options: &DownloadOptions,
+        db: Arc<Mutex<ResumeDb>>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.run(options, db, true)
+    }
+
+    /// Runs just this download on its own `Engine` until it's done.
+    fn run(
+        &mut self,
+        options: &DownloadOptions,
+        db: Arc<Mutex<ResumeDb>>,
+        resume: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut engine = Engine::new(options, db)?;
+        engine.add(self.clone(), resume);
+
+        while !engine.is_idle() {
+            for download in engine.step()? {
+                *self = download;
+            }
         }
 
-        let file_path = &format!("{}/{}", self.file_path, self.file_name);
+        match (&self.status, &self.error) {
+            (DownloadStatus::Completed | DownloadStatus::Skipped, _) => Ok(()),
+            (_, Some(e)) => Err(e.clone().into()),
+            _ => Err("The download did not finish".into()),
+        }
+    }
 
-        // Create the file that we're downloading into
-        let file = File::create(file_path)?;
-        // Create a file_ref for thread sharing
-        let file_ref = Arc::new(Mutex::new(file));
+    /// Where the file is saved on disk. The name never has a directory in it, so the file
+    /// always ends up inside `file_path`.
+    pub fn target_path(&self) -> PathBuf {
+        Path::new(&self.file_path).join(&self.file_name)
+    }
 
-        // Create a cURL easy struct
-        let mut easy = Easy::new();
-        // Allow following redirects for the download
-        easy.follow_location(true)?;
-        // Give it the application as the User Agent
-        easy.useragent("download_it/0.1.0")?;
-        // Pass it the download URL
-        easy.url(&self.url)?;
-        // Get download progress from it
-        easy.progress(true)?;
+    /// Where the file is written until it's complete and verified, so a download that
+    /// stopped early is never left under its final name.
+    fn part_path(&self) -> PathBuf {
+        Path::new(&self.file_path).join(part_file_name(&self.file_name))
+    }
 
-        // If a cookie file was passed, give it to the cURL struct
-        if let Some(cookie) = cookie {
-            easy.cookie(&cookie)?;
+    /// Takes over a partial file left under the final name, like by an older version, so
+    /// it can be resumed from the `.part` file.
+    fn adopt_partial_file(&self) -> Result<(), Box<dyn Error>> {
+        let part_path = self.part_path();
+        let target_path = self.target_path();
+        if !part_path.exists() && target_path.exists() {
+            fs::rename(target_path, part_path)?;
         }
 
-        // If header arguments were given, pass them to the cURL struct
-        if let Some(h_args) = headers {
-            let mut list = curl::easy::List::new();
+        Ok(())
+    }
 
-            for arg in h_args {
-                list.append(&arg)?;
-            }
+    /// Flushes the finished `.part` file to disk and renames it to its final name.
+    fn move_into_place(&self) -> Result<(), String> {
+        let part_path = self.part_path();
+        let target_path = self.target_path();
 
-            easy.http_headers(list)?;
+        File::open(&part_path)
+            .and_then(|file| file.sync_all())
+            .and_then(|_| fs::rename(&part_path, &target_path))
+            .map_err(|e| {
+                format!(
+                    "Could not move {} to {}: {e}",
+                    part_path.display(),
+                    target_path.display()
+                )
+            })?;
+
+        // Make the rename itself survive a crash, where the platform allows syncing a directory
+        if let Ok(dir) = File::open(&self.file_path) {
+            let _ = dir.sync_all();
         }
 
+        Ok(())
+    }
+
+    /// Marks the download as started and records it so an interruption can be resumed.
+    fn start(&mut self, downloaded: u64, db: &Mutex<ResumeDb>) {
         self.status = DownloadStatus::InProgress;
+        self.downloaded = downloaded;
+        self.started.get_or_insert((Instant::now(), downloaded));
+        self.progress_bar.set_message(self.file_name.clone());
+        self.progress_bar.reset_elapsed();
 
-        // Create a progress_bar from Self.progress_bar
-        let progress_bar = Arc::clone(&self.progress_bar);
-        easy.progress_function(move |dl_total, dl_now, _ul_total, _ul_now| {
-            // Update the progress_bar
-            if dl_total > 0.0 {
-                progress_bar.set_length(dl_total as u64);
-                progress_bar.set_position(dl_now as u64);
-            }
-            true
-        })?;
+        if let Err(e) = db.lock().unwrap().create_resume(self) {
+            eprintln!("Could not save {} to the resume database: {e}", self.url);
+        }
+    }
 
-        // Write the data to the file
-        match easy.write_function(move |data| match file_ref.lock().unwrap().write_all(data) {
-            Ok(_) => Ok(data.len()),
-            Err(e) => {
-                eprintln!("Error writing download to the file: {e}");
-                Err(curl::easy::WriteError::Pause)
-            }
-        }) {
+    /// Records how the transfer ended in the download and the resume database.
+    fn finish(&mut self, result: Result<(), String>, db: &Mutex<ResumeDb>) {
+        match result {
             Ok(_) => {
                 self.status = DownloadStatus::Completed;
+                self.error = None;
                 self.progress_bar.finish();
             }
             Err(e) => {
-                self.error = Some(e.to_string());
                 self.status = DownloadStatus::Failed;
-                self.progress_bar.finish();
+                self.error = Some(e);
+                self.progress_bar.abandon();
             }
         };
 
-        // Do the cURL process
-        easy.perform()?;
+        self.save(db);
+    }
 
-        Ok(())
+    /// Checks the finished file against the expected checksum. Files that weren't hashed
+    /// while downloading, like segmented ones, are hashed from disk.
+    fn verify(&self, digest: Option<String>) -> Result<(), String> {
+        if let Some(size) = self.size {
+            let actual = fs::metadata(self.part_path())
+                .map_err(|e| format!("Could not check the size of the downloaded file: {e}"))?
+                .len();
+            if actual != size {
+                return Err(format!(
+                    "Size mismatch: expected {size} bytes, got {actual}"
+                ));
+            }
+        }
+
+        let Some(checksum) = &self.checksum else {
+            return Ok(());
+        };
+
+        let digest = match digest {
+            Some(digest) => digest,
+            None => digest_file(checksum.algorithm, self.part_path())
+                .map_err(|e| format!("Could not hash the downloaded file: {e}"))?,
+        };
+
+        if checksum.matches(&digest) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Checksum mismatch: expected {checksum}, got {}:{digest}",
+                checksum.algorithm.name()
+            ))
+        }
     }
 
-    fn execute_resume(
-        &mut self,
-        cookie: Option<String>,
-        headers: Option<Vec<String>>,
-    ) -> Result<(), Box<dyn Error>> {
-        // Get the file path passed in
-        let file_path = &format!("{}/{}", self.file_path, self.file_name);
+    /// Marks the download as failing verification and moves the file out of the way so it
+    /// can't be mistaken for a good one.
+    fn fail_verification(&mut self, error: String, db: &Mutex<ResumeDb>) {
+        let file_path = self.part_path();
+        let mut quarantine_path = self.target_path().into_os_string();
+        quarantine_path.push(".corrupt");
 
-        // Get the resume position of the file
-        let resume_from = fs::metadata(file_path)?.len();
+        self.status = DownloadStatus::VerificationFailed;
+        self.error = Some(match fs::rename(&file_path, &quarantine_path) {
+            Ok(_) => format!(
+                "{error}, the file was moved to {}",
+                Path::new(&quarantine_path).display()
+            ),
+            Err(_) => {
+                let _ = fs::remove_file(&file_path);
+                format!("{error}, the file was removed")
+            }
+        });
+        self.progress_bar.abandon();
 
-        // Open the file in append mode
-        let file = OpenOptions::new().append(true).open(file_path)?;
+        self.save(db);
+    }
 
-        let file_ref = Arc::new(Mutex::new(file));
+    /// Marks the download as skipped because its file is already there.
+    fn skip(&mut self) {
+        self.status = DownloadStatus::Skipped;
+        self.progress_bar
+            .finish_with_message(format!("{} (already exists)", self.file_name));
+    }
 
-        let mut easy = Easy::new();
-        easy.follow_location(true)?;
-        easy.useragent("download_it/0.1.0")?;
-        easy.url(&self.url)?;
-        easy.progress(true)?;
+    fn save(&self, db: &Mutex<ResumeDb>) {
+        if let Err(e) = db.lock().unwrap().update_resume(self) {
+            eprintln!("Could not update {} in the resume database: {e}", self.url);
+        }
+    }
 
-        // Set HTTP Range header for resume
-        easy.range(&format!("{resume_from}-"))?;
+    /// The validator to send with `If-Range`. Weak ETags aren't allowed there, so those fall
+    /// back to the Last-Modified date.
+    fn if_range_validator(&self) -> Option<&str> {
+        match self.etag.as_deref() {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_deref(),
+        }
+    }
+}
+
+/// How a download's transfers are laid out.
+enum Plan {
+    /// Timing a sample of the file from each mirror to start on the fastest. Each one has
+    /// its time once it's in, `None` if it failed. Then it goes on as a resume if `resume`.
+    Ranking {
+        samples: Vec<(String, Option<Duration>)>,
+        resume: bool,
+    },
+    /// Asking the server about the file before deciding how to download it, with a
+    /// `HEAD` request or, for servers that don't answer those, a request for its first byte.
+    Probing { ranged: bool },
+    /// One transfer for the whole file, starting `resume_from` bytes in.
+    Single { resume_from: u64 },
+    /// One transfer for each unfinished byte range of the file.
+    Segmented { segments: Vec<Segment> },
+}
+
+/// A download the `Engine` is working on.
+struct Active {
+    download: Download,
+    plan: Plan,
+    /// How many of its transfers are still in the `Multi`.
+    running: usize,
+    error: Option<Failure>,
+    needs_restart: bool,
+    /// How many times the download has been retried.
+    attempts: u32,
+    /// When to try the download again after a failure.
+    retry_at: Option<Instant>,
+    /// The file's digest, when it was hashed while downloading.
+    digest: Option<String>,
+    /// Whether to find the checksum in the checksum list once the file has its name.
+    look_up_checksum: bool,
+    /// The name the server gave the file, which the checksum list has it under even when
+    /// it was saved as another to not overwrite a file that was there.
+    served_name: Option<String>,
+    /// The path the download holds in the run's `Claims`.
+    claimed: Option<PathBuf>,
+    /// The file's size, when the preflight request found it out.
+    size: Option<u64>,
+    /// Whether the preflight found the file can be downloaded in segments.
+    split: bool,
+    /// The download's URL and its mirrors, fastest first once they've been timed.
+    sources: Vec<String>,
+    /// Which of the `sources` the transfers come from.
+    source: usize,
+    /// How many times pieces that didn't match their digest have been downloaded again.
+    repairs: usize,
+    /// When the download's progress was last reported.
+    reported_at: Instant,
+}
+
+impl Active {
+    /// The URL the download's transfers are going to.
+    fn url(&self) -> &str {
+        &self.sources[self.source]
+    }
+}
+
+/// Drives every transfer from a single cURL `Multi` handle on one thread, so downloads from
+/// the same host share connections, DNS lookups and TLS sessions.
+pub struct Engine<'a> {
+    // Transfers have to leave the `Multi` before it and the `Share` are cleaned up,
+    // so keep them first to have them dropped first
+    /// Running transfers by token, along with the id of the download they belong to.
+    transfers: HashMap<usize, (usize, Easy2Handle<TransferHandler>)>,
+    multi: Multi,
+    share: Share,
+    downloads: HashMap<usize, Active>,
+    /// Downloads that ended since the last `step`.
+    finished: Vec<Download>,
+    /// The paths the downloads are saving to.
+    claims: Arc<Claims>,
+    next_id: usize,
+    next_token: usize,
+    options: &'a DownloadOptions,
+    db: Arc<Mutex<ResumeDb>>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(
+        options: &'a DownloadOptions,
+        db: Arc<Mutex<ResumeDb>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut multi = Multi::new();
+        // Run transfers to the same server as HTTP/2 streams over one connection when possible
+        multi.pipelining(false, true)?;
+
+        Ok(Self {
+            transfers: HashMap::new(),
+            multi,
+            share: Share::new()?,
+            downloads: HashMap::new(),
+            finished: Vec::new(),
+            claims: Arc::new(Claims::default()),
+            next_id: 0,
+            next_token: 0,
+            options,
+            db,
+        })
+    }
+
+    /// How many downloads are in progress.
+    pub fn active(&self) -> usize {
+        self.downloads.len()
+    }
 
-        // Check if there was a cookie file passed in
-        if let Some(cookie) = cookie {
-            easy.cookie(&cookie)?;
+    /// Whether there is nothing left running or waiting to be reported by `step`.
+    pub fn is_idle(&self) -> bool {
+        self.downloads.is_empty() && self.finished.is_empty()
+    }
+
+    /// Starts a download, or picks it up where it stopped if `resume` is set.
+    pub fn add(&mut self, download: Download, resume: bool) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // The bar still keeps count for the progress events
+        if !self.options.output.bars() {
+            download
+                .progress_bar
+                .set_draw_target(ProgressDrawTarget::hidden());
         }
 
-        // Check if there are headers and add them if there are
-        if let Some(headers) = headers {
-            let mut list = curl::easy::List::new();
+        // A new download that failed before it was queued, like an input file entry with a bad
+        // option, is only reported
+        if !resume && download.status == DownloadStatus::Failed {
+            download.progress_bar.abandon();
+            self.report_end(&download);
+            return self.finished.push(download);
+        }
 
-            for header in headers {
-                list.append(&header)?;
+        let mut sources = vec![download.url.clone()];
+        for mirror in &download.mirrors {
+            if !sources.contains(mirror) {
+                sources.push(mirror.clone());
             }
+        }
+
+        let mut active = Active {
+            download,
+            plan: Plan::Probing { ranged: false },
+            running: 0,
+            error: None,
+            needs_restart: false,
+            attempts: 0,
+            retry_at: None,
+            digest: None,
+            look_up_checksum: false,
+            served_name: None,
+            claimed: None,
+            size: None,
+            split: false,
+            sources,
+            source: 0,
+            repairs: 0,
+            reported_at: Instant::now(),
+        };
 
-            easy.http_headers(list)?;
+        // A file missing from the checksum list can't be trusted, so don't download it.
+        // Files named after the response are looked up once they have their name.
+        if self.options.checksum_list.is_some() && active.download.checksum.is_none() {
+            if active.download.name_from_response {
+                active.look_up_checksum = true;
+            } else {
+                match self.look_up_checksum(&active.download.file_name) {
+                    Ok(checksum) => active.download.checksum = checksum,
+                    Err(e) => return self.finish(active, Err(e)),
+                }
+            }
         }
 
-        self.status = DownloadStatus::InProgress;
+        let started = if active.sources.len() > 1 {
+            self.begin_ranking(id, &mut active, resume)
+        } else if resume {
+            self.begin_claimed(id, &mut active, true)
+        } else {
+            self.begin(id, &mut active)
+        };
+
+        match started {
+            Ok(_) => self.keep_or_finish(id, active),
+            Err(e) => self.finish(active, Err(e.to_string())),
+        }
+    }
 
-        let progress_bar = Arc::clone(&self.progress_bar);
-        easy.progress_function(move |dl_total, dl_now, _ul_total, _ul_now| {
-            // Update the progress bar
-            if dl_total > 0.0 {
-                progress_bar.set_length(dl_total as u64);
-                progress_bar.set_position(dl_now as u64);
+    /// Moves every transfer along, waiting a little for network activity when there is
+    /// nothing to do. Returns the downloads that ended since the last call.
+    pub fn step(&mut self) -> Result<Vec<Download>, Box<dyn Error>> {
+        self.start_retries();
+        self.multi.perform()?;
+
+        let mut done = Vec::new();
+        // Going through the handle gets cURL's description of what went wrong, like a stall
+        self.multi.messages(|message| {
+            if let Ok(token) = message.token()
+                && let Some((_, handle)) = self.transfers.get(&token)
+                && let Some(result) = message.result_for2(handle)
+            {
+                done.push((token, result));
             }
-            true
-        })?;
+        });
 
-        // Write the data to the file
-        match easy.write_function(move |data| match file_ref.lock().unwrap().write_all(data) {
-            Ok(_) => Ok(data.len()),
-            Err(e) => {
-                eprintln!("Error writing download to the file: {e}");
-                Err(curl::easy::WriteError::Pause)
+        for (token, result) in done {
+            self.transfer_done(token, result)?;
+        }
+
+        let paused = self.unpause_transfers()?;
+        self.report_progress();
+
+        if !self.downloads.is_empty() && self.finished.is_empty() {
+            // Don't sleep past the next retry
+            let timeout = self
+                .downloads
+                .values()
+                .filter_map(|active| active.retry_at)
+                .min()
+                .map_or(WAIT_TIMEOUT, |retry_at| {
+                    retry_at
+                        .saturating_duration_since(Instant::now())
+                        .min(WAIT_TIMEOUT)
+                });
+            // cURL won't wake up for paused transfers, so check back on them soon
+            let timeout = if paused {
+                timeout.min(PAUSE_CHECK_INTERVAL)
+            } else {
+                timeout
+            };
+
+            if self.transfers.is_empty() {
+                thread::sleep(timeout);
+            } else {
+                self.multi.wait(&mut [], timeout)?;
             }
-        }) {
-            Ok(_) => {
-                self.status = DownloadStatus::Completed;
-                self.progress_bar.finish();
+        }
+
+        Ok(std::mem::take(&mut self.finished))
+    }
+
+    /// Reports how far along each running download is, every so often when there are no
+    /// progress bars to show it.
+    fn report_progress(&mut self) {
+        let Some(interval) = self.options.output.progress_interval() else {
+            return;
+        };
+
+        for active in self.downloads.values_mut() {
+            let download = &active.download;
+            if download.status != DownloadStatus::InProgress
+                || active.running == 0
+                || active.reported_at.elapsed() < interval
+            {
+                continue;
             }
-            Err(e) => {
-                self.error = Some(e.to_string());
-                self.status = DownloadStatus::Failed;
-                self.progress_bar.finish();
+
+            active.reported_at = Instant::now();
+            self.options.output.emit(Event::Progress {
+                url: &download.url,
+                bytes: download.progress_bar.position(),
+                total: download.progress_bar.length(),
+                speed: download.progress_bar.per_sec() as u64,
+            });
+        }
+    }
+
+    /// Lets transfers the rate limiter held back carry on once it has bytes to give.
+    /// Returns whether any are still paused.
+    fn unpause_transfers(&mut self) -> Result<bool, Box<dyn Error>> {
+        let mut paused = false;
+
+        for (_, handle) in self.transfers.values_mut() {
+            if !handle.get_ref().paused {
+                continue;
+            }
+
+            if self.options.total_rate_limiter.ready() {
+                handle.get_mut().paused = false;
+                handle.unpause_write()?;
+            }
+            paused |= handle.get_ref().paused;
+        }
+
+        Ok(paused)
+    }
+
+    /// Starts the downloads whose retry delay is up again, from where they stopped.
+    fn start_retries(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .downloads
+            .iter()
+            .filter(|(_, active)| active.retry_at.is_some_and(|retry_at| retry_at <= now))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in due {
+            let Some(mut active) = self.downloads.remove(&id) else {
+                continue;
+            };
+            active.retry_at = None;
+
+            // Segments and partial files are in the resume database, so pick those up.
+            // Nothing was written yet if the file never got its name.
+            let started = match active.plan {
+                Plan::Probing { .. } => self.begin(id, &mut active),
+                _ if active.download.name_from_response => self.begin_from_start(id, &mut active),
+                _ => self.begin_resume(id, &mut active),
+            };
+
+            match started {
+                Ok(_) => self.keep_or_finish(id, active),
+                Err(e) => self.finish(active, Err(e.to_string())),
             }
+        }
+    }
+
+    /// Schedules another try of a download that failed for a reason that may pass,
+    /// or finishes it as failed when it's out of retries.
+    fn fail(&mut self, id: usize, mut active: Active, failure: Failure) {
+        // Another mirror may well have the file, so go straight on to it. Going through
+        // all of them only counts as one try.
+        if failure.from_server && active.sources.len() > 1 {
+            let failed_url = active.url().to_string();
+            active.source = (active.source + 1) % active.sources.len();
+            // Mirrors don't share ETags, so the file can't be checked against them when resuming
+            active.download.etag = None;
+            active.download.last_modified = None;
+
+            if active.source != 0 {
+                let download = &active.download;
+                self.options.output.println(
+                    &download.progress_bar,
+                    format!(
+                        "{failed_url} failed: {}. Switching to {} at byte {}.",
+                        failure.message,
+                        active.url(),
+                        download.downloaded
+                    ),
+                );
+                self.options.output.emit(Event::Retry {
+                    url: &download.url,
+                    next_url: active.url(),
+                    attempt: active.attempts,
+                    retries: self.options.retries,
+                    delay: 0.0,
+                    error: &failure.message,
+                });
+                active.retry_at = Some(Instant::now());
+                self.downloads.insert(id, active);
+                return;
+            }
+        }
+
+        if !failure.transient || active.attempts >= self.options.retries {
+            return self.finish(active, Err(failure.message));
+        }
+
+        // Back off exponentially, unless the server said how long to wait
+        let backoff = self
+            .options
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(active.attempts));
+        let delay = failure
+            .retry_after
+            .unwrap_or(backoff)
+            .min(self.options.max_retry_delay);
+
+        active.attempts += 1;
+        active.retry_at = Some(Instant::now() + delay);
+
+        // Keep why it failed in the resume database in case the run doesn't get to retry it
+        active.download.error = Some(failure.message.clone());
+        active.download.save(&self.db);
+
+        let download = &active.download;
+        self.options.output.println(
+            &download.progress_bar,
+            format!(
+                "{} failed: {}. Retrying in {}s ({} of {}).",
+                active.url(),
+                failure.message,
+                delay.as_secs_f32(),
+                active.attempts,
+                self.options.retries
+            ),
+        );
+        self.options.output.emit(Event::Retry {
+            url: &download.url,
+            next_url: active.url(),
+            attempt: active.attempts,
+            retries: self.options.retries,
+            delay: delay.as_secs_f64(),
+            error: &failure.message,
+        });
+        download
+            .progress_bar
+            .set_message(format!("{} (waiting to retry)", download.file_name));
+
+        self.downloads.insert(id, active);
+    }
+
+    fn begin(&mut self, id: usize, active: &mut Active) -> Result<(), Box<dyn Error>> {
+        let download = &active.download;
+
+        // Create the download file path
+        let file_path = Path::new(&download.file_path);
+        if !file_path.exists() {
+            std::fs::create_dir_all(file_path)?;
+        }
+
+        // Find out how big the file is and whether the server can send byte ranges before
+        // opening anything
+        self.begin_probe(id, active, false)
+    }
+
+    /// Fetches the start of the file from every mirror at once, to find which sends it the
+    /// quickest counting both how long it takes to answer and how fast the data comes.
+    fn begin_ranking(
+        &mut self,
+        id: usize,
+        active: &mut Active,
+        resume: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        active.plan = Plan::Ranking {
+            samples: Vec::new(),
+            resume,
         };
+        active.download.progress_bar.set_message(format!(
+            "{} (finding the fastest mirror)",
+            active.download.file_name
+        ));
+
+        for source in 0..active.sources.len() {
+            active.source = source;
+            let handler = TransferHandler::sample(
+                active.url().to_string(),
+                MIRROR_SAMPLE_SIZE,
+                Arc::clone(&active.download.progress_bar),
+                Arc::clone(&self.db),
+            );
+            let mut easy = self.new_easy(handler, active, Vec::new())?;
+            easy.range(&format!("0-{}", MIRROR_SAMPLE_SIZE - 1))?;
 
-        // Do the cURL process
-        easy.perform()?;
+            self.add_transfer(id, active, easy)?;
+        }
+        active.source = 0;
 
         Ok(())
     }
-}
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub enum DownloadStatus {
-    Pending,
-    InProgress,
-    Completed,
-    Failed,
-}
+    /// Records how a mirror's sample went, and once they're all in starts the download from
+    /// the fastest.
+    fn sample_done(
+        &mut self,
+        id: usize,
+        mut active: Active,
+        easy: &mut Easy2<TransferHandler>,
+        result: Result<(), curl::Error>,
+    ) {
+        let Plan::Ranking { samples, resume } = &mut active.plan else {
+            return;
+        };
 
-impl PartialEq for DownloadStatus {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Pending, Self::Pending)
-            | (Self::InProgress, Self::InProgress)
-            | (Self::Completed, Self::Completed)
-            | (Self::Failed, Self::Failed) => true,
-            _ => false,
+        // The sample is cut off on purpose once it has enough
+        let handler = easy.get_ref();
+        let sampled = (200..300).contains(&handler.response.status)
+            && (result.is_ok() || handler.written >= MIRROR_SAMPLE_SIZE);
+        let time = easy.total_time().ok().filter(|_| sampled);
+        samples.push((easy.get_ref().url().to_string(), time));
+
+        if active.running > 0 {
+            self.downloads.insert(id, active);
+            return;
+        }
+
+        // Mirrors that failed go last, in case they're back by the time they're needed
+        let resume = *resume;
+        let mut samples = std::mem::take(samples);
+        samples.sort_by_key(|(_, time)| time.unwrap_or(Duration::MAX));
+        let none_answered = samples.first().is_none_or(|(_, time)| time.is_none());
+        active.sources = samples.into_iter().map(|(url, _)| url).collect();
+        active.source = 0;
+
+        let download = &active.download;
+        if none_answered {
+            self.options.output.println(
+                &download.progress_bar,
+                format!(
+                    "None of the mirrors of {} answered, trying them anyway.",
+                    download.url
+                ),
+            );
+        } else {
+            self.options.output.println(
+                &download.progress_bar,
+                format!(
+                    "Downloading {} from {}, the fastest of {} mirrors.",
+                    download.file_name,
+                    active.url(),
+                    active.sources.len()
+                ),
+            );
+        }
+
+        active.plan = Plan::Probing { ranged: false };
+        let started = if resume {
+            self.begin_claimed(id, &mut active, true)
+        } else {
+            self.begin(id, &mut active)
+        };
+
+        match started {
+            Ok(_) => self.keep_or_finish(id, active),
+            Err(e) => self.finish(active, Err(e.to_string())),
         }
     }
-}
 
-pub fn download_single_resume(
-    url: &str,
-    file_path: Option<String>,
-    file_name: Option<String>,
-    cookie: Option<String>,
-    header_args: Option<Vec<String>>,
-    download: Option<Download>,
-) -> Result<(), Box<dyn Error>> {
-    if file_path.is_none() || file_name.is_none() {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Cannot resume without a file path or file name",
-        )));
-    }
-
-    let mut download = if let Some(download) = download {
-        download
-    } else {
-        Download::new(url.into(), file_name, file_path, None)
-    };
+    /// Asks the server about the file without downloading it.
+    fn begin_probe(
+        &mut self,
+        id: usize,
+        active: &mut Active,
+        ranged: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let download = &active.download;
+        let handler = TransferHandler::probe(
+            download.url.clone(),
+            Arc::clone(&download.progress_bar),
+            Arc::clone(&self.db),
+        );
+        let mut easy = self.new_easy(handler, active, Vec::new())?;
+        if ranged {
+            easy.range("0-0")?;
+        } else {
+            easy.nobody(true)?;
+        }
 
-    download.execute_resume(cookie, header_args)?;
+        active.plan = Plan::Probing { ranged };
+        self.add_transfer(id, active, easy)
+    }
 
-    Ok(())
-}
+    /// Refuses a file the preflight found too big for `--max-size` or the free space left
+    /// where it's going.
+    fn check_size(&self, download: &Download, size: u64) -> Result<(), String> {
+        if let Some(max_size) = self.options.max_size
+            && size > max_size
+        {
+            return Err(format!(
+                "The file is {}, more than the --max-size of {}",
+                HumanBytes(size),
+                HumanBytes(max_size)
+            ));
+        }
 
-pub fn download_single(
-    url: String,
-    file_path: Option<String>,
-    file_name: Option<String>,
-    cookie: Option<String>,
-    header_args: Option<Vec<String>>,
-    download: Option<Download>,
-) -> Result<(), Box<dyn Error>> {
-    let mut download = if let Some(download) = download {
-        download
-    } else {
-        Download::new(url, file_name, file_path, None)
-    };
+        // Not being able to tell shouldn't stop the download
+        if let Ok(available) = fs4::available_space(&download.file_path)
+            && size > available
+        {
+            return Err(format!(
+                "The file is {} but only {} is free in {}",
+                HumanBytes(size),
+                HumanBytes(available),
+                download.file_path
+            ));
+        }
 
-    download.execute(cookie, header_args)?;
+        Ok(())
+    }
 
-    Ok(())
-}
+    /// Checks the file against what's on disk and the other downloads in the run before
+    /// anything is written, then starts it the way `--on-conflict` says.
+    fn begin_claimed(
+        &mut self,
+        id: usize,
+        active: &mut Active,
+        resuming: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let download = &mut active.download;
+        let resolution = self.claims.resolve(
+            self.options.on_conflict,
+            &download.file_path,
+            &download.file_name,
+            resuming,
+        )?;
 
-pub fn download_multi(
-    urls: &Vec<String>,
-    file_path: Option<Vec<String>>,
-    file_names: Option<Vec<String>>,
-    cookie: Option<String>,
-    header_args: Option<Vec<String>>,
-) -> Result<(), Box<dyn Error>> {
-    thread::scope(|s| {
-        let mut threads = Vec::new();
-        let multi_progress = MultiProgress::new();
-        for (idx, url) in urls.into_iter().enumerate() {
-            let url = url.clone();
-            let cookie = cookie.clone();
-            let header_args = header_args.clone();
-            let file_name = if let Some(file_names) = file_names.clone() {
-                if file_names.len() == urls.len() {
-                    Some(file_names[idx].clone())
-                } else {
-                    None
+        match resolution {
+            Resolution::Create(file_name) => {
+                download.file_name = file_name;
+                active.claimed = Some(download.target_path());
+                // Whatever was saved about a partial file doesn't go with the new one
+                if resuming {
+                    let _ = self
+                        .db
+                        .lock()
+                        .unwrap()
+                        .delete_segments(&download.url, &download.target_path());
+                    download.etag = None;
+                    download.last_modified = None;
                 }
-            } else {
-                None
-            };
-            let file_path = if let Some(file_path) = file_path.clone() {
-                if file_path.len() == 1 {
-                    file_path[0].clone()
-                } else if file_path.len() == urls.len() {
-                    file_path[idx].clone()
+
+                match active.size {
+                    Some(size) if active.split => {
+                        let segments = Segment::split(size, self.options.segments);
+                        self.begin_segmented(id, active, size, segments)
+                    }
+                    _ => self.begin_from_start(id, active),
+                }
+            }
+            Resolution::Resume => {
+                active.claimed = Some(download.target_path());
+                if resuming {
+                    download.adopt_partial_file()?;
+                    self.begin_resume(id, active)
                 } else {
-                    dirs::download_dir()
-                        .expect("Download directory")
-                        .to_string_lossy()
-                        .to_string()
+                    self.resume_existing(id, active)
                 }
+            }
+            Resolution::Skip => {
+                download.skip();
+                Ok(())
+            }
+        }
+    }
+
+    /// Downloads the whole file over one connection into a new file.
+    fn begin_from_start(&mut self, id: usize, active: &mut Active) -> Result<(), Box<dyn Error>> {
+        // The file is created once the response says what to call it
+        if active.download.name_from_response {
+            return self.begin_single(id, active, None, 0);
+        }
+
+        // Create the file that we're downloading into
+        let file = File::create(active.download.part_path())?;
+        if let Some(size) = active.size {
+            preallocate(&file, size)?;
+        }
+
+        self.begin_single(id, active, Some(file), 0)
+    }
+
+    /// Picks up a file that was already there as the start of the download.
+    fn resume_existing(&mut self, id: usize, active: &mut Active) -> Result<(), Box<dyn Error>> {
+        let download = &mut active.download;
+        // A `.part` file may have had its whole size set aside up front, so trust what the
+        // resume database saw written to it over its length. A `.part` file it has nothing on,
+        // or a file under the final name, is taken whole.
+        download.downloaded = if let Ok(metadata) = fs::metadata(download.part_path()) {
+            self.db
+                .lock()
+                .unwrap()
+                .get_resume(&download.url, &download.target_path())?
+                .map_or(metadata.len(), |saved| saved.downloaded.min(metadata.len()))
+        } else {
+            download.adopt_partial_file()?;
+            fs::metadata(download.part_path()).map_or(0, |m| m.len())
+        };
+
+        self.begin_resume(id, active)
+    }
+
+    fn begin_resume(&mut self, id: usize, active: &mut Active) -> Result<(), Box<dyn Error>> {
+        let download = &active.download;
+        // Get the file path passed in
+        let file_path = download.part_path();
+
+        // Make sure the download directory still exists
+        let dir_path = Path::new(&download.file_path);
+        if !dir_path.exists() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        // Segmented downloads pick up each of their byte ranges where it stopped
+        let segments = self
+            .db
+            .lock()
+            .unwrap()
+            .get_segments(&download.url, &download.target_path())?;
+        if let Some(last) = segments.last() {
+            let size = last.end + 1;
+            let segments = if file_path.exists() {
+                segments
             } else {
-                dirs::download_dir()
-                    .expect("Download directory")
-                    .to_string_lossy()
-                    .to_string()
+                Segment::split(size, segments.len())
             };
-            let download = Download::new(
+
+            return self.begin_segmented(id, active, size, segments);
+        }
+
+        // Get the resume position of the file, starting over if the partial file is gone.
+        // Space set aside up front makes the file longer than what was written to it.
+        let resume_from = match fs::metadata(&file_path) {
+            Ok(metadata) => metadata.len().min(download.downloaded),
+            Err(e) if e.kind() == IoErrorKind::NotFound => 0,
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        // Open the file without truncating it, the transfer seeks to the end once the server
+        // confirms it is sending the rest of the file. It's read back in to hash it.
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&file_path)?;
+
+        self.begin_single(id, active, Some(file), resume_from)
+    }
+
+    /// Starts one transfer into `file`, starting `resume_from` bytes into the remote file.
+    /// Without a file, one is created once the response says what to call it.
+    fn begin_single(
+        &mut self,
+        id: usize,
+        active: &mut Active,
+        file: Option<File>,
+        resume_from: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let download = &mut active.download;
+        let progress_bar = Arc::clone(&download.progress_bar);
+        let mut handler = match file {
+            Some(file) => TransferHandler::new(
+                download.url.clone(),
+                download.target_path(),
+                file,
+                resume_from,
+                progress_bar,
+                Arc::clone(&self.db),
+            ),
+            None => TransferHandler::unnamed(
+                download.url.clone(),
+                download.target_path(),
+                download.file_path.clone(),
+                Arc::clone(&self.claims),
+                self.options.on_conflict,
+                progress_bar,
+                Arc::clone(&self.db),
+            ),
+        };
+        if let Some(checksum) = &download.checksum {
+            handler.hash_with(checksum.algorithm);
+        }
+
+        // Only take the range if the file hasn't changed, otherwise the server sends it all
+        let mut headers = Vec::new();
+        if resume_from > 0
+            && let Some(validator) = download.if_range_validator()
+        {
+            headers.push(format!("If-Range: {validator}"));
+        }
+
+        let mut easy = self.new_easy(handler, active, headers)?;
+
+        // Set HTTP Range header for resume
+        if resume_from > 0 {
+            easy.range(&format!("{resume_from}-"))?;
+        }
+
+        if let Some(rate) = self.options.limit_rate {
+            easy.max_recv_speed(rate)?;
+        }
+
+        active.download.start(resume_from, &self.db);
+        self.report_start(active);
+        active.plan = Plan::Single { resume_from };
+
+        self.add_transfer(id, active, easy)
+    }
+
+    /// Starts a transfer for each unfinished segment of a `size` byte file, each written
+    /// straight to its place in the file.
+    fn begin_segmented(
+        &mut self,
+        id: usize,
+        active: &mut Active,
+        size: u64,
+        segments: Vec<Segment>,
+    ) -> Result<(), Box<dyn Error>> {
+        let download = &mut active.download;
+        let file_path = download.part_path();
+
+        // Set aside the whole file up front so every segment can write at its own offset
+        let fresh = segments.iter().all(|segment| segment.downloaded == 0);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(fresh)
+            .write(true)
+            .open(&file_path)?;
+        file.set_len(size)?;
+        preallocate(&file, size)?;
+
+        let downloaded = segments.iter().map(|segment| segment.downloaded).sum();
+        download.progress_bar.set_length(size);
+        download.progress_bar.set_position(downloaded);
+        download.start(downloaded, &self.db);
+        self.report_start(active);
+        let download = &active.download;
+        if let Err(e) = self.db.lock().unwrap().create_segments(
+            &download.url,
+            &download.target_path(),
+            &segments,
+        ) {
+            eprintln!(
+                "Could not save the segments of {} to the resume database: {e}",
+                download.url
+            );
+        }
+
+        let url = download.url.clone();
+        let target = download.target_path();
+        let progress_bar = Arc::clone(&download.progress_bar);
+        // Every segment has to come from the same version of the file
+        let validator = download.if_range_validator().map(str::to_string);
+
+        active.plan = Plan::Segmented {
+            segments: segments.clone(),
+        };
+
+        let unfinished = segments
+            .into_iter()
+            .filter(|segment| !segment.is_complete())
+            .collect::<Vec<_>>();
+        // The download's rate limit is shared out between its segments
+        let limit_rate = self
+            .options
+            .limit_rate
+            .map(|rate| (rate / unfinished.len().max(1) as u64).max(1));
+
+        for segment in unfinished {
+            let file = OpenOptions::new().write(true).open(&file_path)?;
+            let handler = TransferHandler::for_segment(
                 url.clone(),
-                file_name.clone(),
-                Some(file_path.clone()),
-                Some(&multi_progress),
+                target.clone(),
+                file,
+                segment,
+                Arc::clone(&progress_bar),
+                Arc::clone(&self.db),
             );
-            threads.push(s.spawn(move || {
-                match download_single(
-                    url.clone(),
-                    Some(file_path.clone()),
-                    file_name.clone(),
-                    cookie,
-                    header_args,
-                    Some(download),
-                ) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{url} failed to download: {e}");
-                    }
-                };
-            }));
+            let headers = validator
+                .iter()
+                .map(|validator| format!("If-Range: {validator}"))
+                .collect();
+            let mut easy = self.new_easy(handler, active, headers)?;
+            let start = segment.start + segment.downloaded;
+            easy.range(&format!("{start}-{}", segment.end))?;
+            if let Some(rate) = limit_rate {
+                easy.max_recv_speed(rate)?;
+            }
+
+            self.add_transfer(id, active, easy)?;
+        }
+
+        Ok(())
+    }
+
+    fn report_start(&self, active: &mut Active) {
+        active.reported_at = Instant::now();
+        let download = &active.download;
+        self.options.output.emit(Event::Started {
+            url: &download.url,
+            path: download.target_path().display().to_string(),
+            resumed_from: download.downloaded,
+        });
+    }
+
+    /// Builds a cURL handle for the download's current URL with the run's cookie, headers and
+    /// shared caches.
+    fn new_easy(
+        &self,
+        mut handler: TransferHandler,
+        active: &Active,
+        extra_headers: Vec<String>,
+    ) -> Result<Easy2<TransferHandler>, Box<dyn Error>> {
+        let download = &active.download;
+        handler.limit_with(Arc::clone(&self.options.total_rate_limiter));
+        if let Some(max_size) = self.options.max_size {
+            handler.cap_size(max_size);
         }
 
-        for t in threads {
-            t.join()
-                .expect("There was a thread that failed to rejoin the main thread!");
+        let mut headers = self.options.headers.clone();
+        headers.extend(download.headers.iter().cloned());
+        headers.extend(extra_headers);
+        let cookie = download
+            .cookie
+            .as_deref()
+            .or(self.options.cookie.as_deref());
+
+        let mut easy = new_easy(handler, active.url(), cookie, &headers)?;
+        self.share.attach(&mut easy)?;
+
+        if let Some(timeout) = self.options.connect_timeout {
+            easy.connect_timeout(timeout)?;
+        }
+        if let Some(timeout) = self.options.timeout {
+            easy.timeout(timeout)?;
+        }
+        // Stop a transfer that has stalled so it can be resumed, from another mirror if
+        // there is one, instead of waiting on it forever
+        if !self.options.stall_time.is_zero() {
+            easy.low_speed_limit(u32::try_from(self.options.stall_speed).unwrap_or(u32::MAX))?;
+            easy.low_speed_time(self.options.stall_time)?;
         }
-    });
 
-    Ok(())
-}
+        Ok(easy)
+    }
+
+    fn add_transfer(
+        &mut self,
+        id: usize,
+        active: &mut Active,
+        easy: Easy2<TransferHandler>,
+    ) -> Result<(), Box<dyn Error>> {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        let mut handle = self.multi.add2(easy)?;
+        handle.set_token(token)?;
+        self.transfers.insert(token, (id, handle));
+        active.running += 1;
+
+        Ok(())
+    }
+
+    /// Handles a transfer that cURL is done with, moving its download on to the next step.
+    fn transfer_done(
+        &mut self,
+        token: usize,
+        result: Result<(), curl::Error>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some((id, handle)) = self.transfers.remove(&token) else {
+            return Ok(());
+        };
+        let mut easy = self.multi.remove2(handle)?;
+        let Some(mut active) = self.downloads.remove(&id) else {
+            return Ok(());
+        };
+        active.running -= 1;
+
+        if let Plan::Ranking { .. } = active.plan {
+            self.sample_done(id, active, &mut easy, result);
+            return Ok(());
+        }
+
+        let handler = easy.get_mut();
+        let download = &mut active.download;
+        if let Some(file_name) = handler.file_name.take() {
+            active.served_name = handler.served_name.take();
+            download.file_name = file_name;
+            download.name_from_response = false;
+            if handler.conflict != Some(Resolution::Skip) {
+                active.claimed = Some(download.target_path());
+            }
+        }
+        if handler.response.etag.is_some() || handler.response.last_modified.is_some() {
+            download.etag = handler.response.etag.clone();
+            download.last_modified = handler.response.last_modified.clone();
+        }
+
+        match &mut active.plan {
+            Plan::Ranking { .. } => {}
+            Plan::Probing { ranged } => {
+                let ranged = *ranged;
+                let response = &handler.response;
+                // The ranged request is cut off once its headers are in
+                let probed = result.is_ok() || (ranged && (200..300).contains(&response.status));
+
+                // Only go ahead without knowing about the file if the server really can't
+                // be asked
+                if !probed && let Err(e) = &result {
+                    let failure = handler.failure(e);
+                    if failure.transient {
+                        self.fail(id, active, failure);
+                        return Ok(());
+                    }
+                    if !ranged {
+                        match self.begin_probe(id, &mut active, true) {
+                            Ok(_) => self.keep_or_finish(id, active),
+                            Err(e) => self.finish(active, Err(e.to_string())),
+                        }
+                        return Ok(());
+                    }
+                }
+
+                if probed && download.name_from_response {
+                    if let Some(file_name) =
+                        file_name_from_response(response, &handler.effective_url)
+                    {
+                        download.file_name = file_name;
+                    }
+                    download.name_from_response = false;
 
-pub fn download_multi_resume(
-    urls: &Vec<String>,
-    file_paths: Option<Vec<String>>,
-    file_names: Option<Vec<String>>,
-    cookie: Option<String>,
-    header_args: Option<Vec<String>>,
-) -> Result<(), Box<dyn Error>> {
-    if file_paths.is_none() || file_names.is_none() {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "You must enter at least on file path and file name to resume.",
-        )));
-    }
-
-    thread::scope(|s| {
-        let mut threads = Vec::new();
-        let multi_progress = MultiProgress::new();
-        for (idx, url) in urls.into_iter().enumerate() {
-            let url = url.clone();
-            let cookie = cookie.clone();
-            let header_args = header_args.clone();
-            let file_path = match file_paths.clone().unwrap().clone().iter().nth(idx) {
-                Some(file_path) => Some(file_path.clone()),
-                None => {
-                    eprintln!("The file_path argument did not have a value for this download!");
-                    eprintln!("Skipping download for {url}!");
-                    continue;
+                    // The list has the file under the name the server gave it, so look it up
+                    // before it can be renamed to keep from overwriting another file
+                    if active.look_up_checksum {
+                        active.look_up_checksum = false;
+                        match self.look_up_checksum(&active.download.file_name) {
+                            Ok(checksum) => active.download.checksum = checksum,
+                            Err(e) => {
+                                self.finish(active, Err(e));
+                                return Ok(());
+                            }
+                        }
+                    }
                 }
-            };
-            let file_name = match file_names.clone().unwrap().clone().iter().nth(idx) {
-                Some(file_name) => Some(file_name.clone()),
-                None => {
-                    eprintln!("The file_names argument did not have a value for this download!");
-                    eprintln!("Skipping download for {url}!");
-                    continue;
+
+                if probed {
+                    active.size = response.file_size();
+                    active.split = self.options.segments > 1
+                        && response.supports_ranges()
+                        && active.size.is_some_and(|size| size >= MIN_SEGMENT_SIZE * 2);
                 }
-            };
-            let download = Download::new(
-                url.clone(),
-                file_name.clone(),
-                file_path.clone(),
-                Some(&multi_progress),
-            );
-            threads.push(s.spawn(move || {
-                match download_single(
-                    url.clone(),
-                    file_path.clone(),
-                    file_name.clone(),
-                    cookie,
-                    header_args,
-                    Some(download),
-                ) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{url} failed to download: {e}");
+                // A mirror with a different file can be left for one with the right one
+                if let (Some(expected), Some(size)) = (active.download.size, active.size)
+                    && size != expected
+                {
+                    let failure = Failure {
+                        message: format!("The server has {size} bytes instead of {expected}"),
+                        transient: false,
+                        retry_after: None,
+                        from_server: true,
+                    };
+                    self.fail(id, active, failure);
+                    return Ok(());
+                }
+                if let Some(size) = active.size
+                    && let Err(e) = self.check_size(&active.download, size)
+                {
+                    self.finish(active, Err(e));
+                    return Ok(());
+                }
+
+                let download = &active.download;
+                if self.options.segments > 1 && !active.split {
+                    self.options.output.println(
+                        &download.progress_bar,
+                        format!(
+                            "{} can't be split into segments, downloading it over one connection.",
+                            download.url
+                        ),
+                    );
+                }
+                let started = if download.name_from_response {
+                    self.begin_from_start(id, &mut active)
+                } else {
+                    self.begin_claimed(id, &mut active, false)
+                };
+
+                match started {
+                    Ok(_) => self.keep_or_finish(id, active),
+                    Err(e) => self.finish(active, Err(e.to_string())),
+                }
+            }
+            // The file the response named was already there
+            Plan::Single { .. } if handler.conflict.is_some() => {
+                let started = match handler.conflict.take() {
+                    Some(Resolution::Resume) => self.resume_existing(id, &mut active),
+                    _ => {
+                        download.skip();
+                        Ok(())
+                    }
+                };
+
+                match started {
+                    Ok(_) => self.keep_or_finish(id, active),
+                    Err(e) => self.finish(active, Err(e.to_string())),
+                }
+            }
+            Plan::Single { resume_from } => {
+                let resume_from = *resume_from;
+                download.downloaded = handler.downloaded();
+                active.digest = handler.take_digest();
+
+                let result = match result {
+                    // The server has nothing past the end of what we have, so the file is
+                    // already whole
+                    Err(_) if resume_from > 0 && handler.response.status == 416 => {
+                        match handler.response.content_range {
+                            Some(ContentRange {
+                                total: Some(total), ..
+                            }) if total == resume_from => Ok(()),
+                            _ => Err(Failure::permanent(
+                                "The server rejected the resume range (HTTP 416)",
+                            )),
+                        }
                     }
+                    Err(e) => Err(handler.failure(&e)),
+                    Ok(_) => Ok(()),
                 };
-            }));
+
+                match result {
+                    Ok(_) => {
+                        let trimmed = handler
+                            .trim_file()
+                            .map_err(|e| format!("Could not trim the downloaded file: {e}"));
+                        self.finish(active, trimmed)
+                    }
+                    Err(failure) => self.fail(id, active, failure),
+                }
+            }
+            Plan::Segmented { segments } => {
+                if let Some(segment) = handler.segment() {
+                    segments[segment.index] = segment;
+                }
+                active.needs_restart |= handler.needs_restart;
+                if let Err(e) = result {
+                    active.error.get_or_insert_with(|| handler.failure(&e));
+                }
+
+                if active.running == 0 {
+                    self.segments_done(id, active);
+                } else {
+                    self.downloads.insert(id, active);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wraps up a segmented download once all of its segments have stopped.
+    fn segments_done(&mut self, id: usize, mut active: Active) {
+        let Plan::Segmented { segments } = &active.plan else {
+            return;
+        };
+        let download = &mut active.download;
+
+        // The file changed on the server or it doesn't really do ranges, so the segments
+        // on disk can't be trusted and it has to be downloaded again from the start
+        if active.needs_restart {
+            self.options.output.println(
+                &download.progress_bar,
+                format!(
+                    "{} could not be downloaded in segments, restarting it over one connection.",
+                    download.url
+                ),
+            );
+            let _ = self
+                .db
+                .lock()
+                .unwrap()
+                .delete_segments(&download.url, &download.target_path());
+            download.etag = None;
+            download.last_modified = None;
+            download.progress_bar.set_position(0);
+            active.needs_restart = false;
+            active.error = None;
+
+            return match self.begin_from_start(id, &mut active) {
+                Ok(_) => self.keep_or_finish(id, active),
+                Err(e) => self.finish(active, Err(e.to_string())),
+            };
+        }
+
+        {
+            let db = self.db.lock().unwrap();
+            for segment in segments {
+                let _ = db.update_segment(&download.url, &download.target_path(), segment);
+            }
+        }
+
+        download.downloaded = segments.iter().map(|segment| segment.downloaded).sum();
+        match active.error.take() {
+            Some(failure) => self.fail(id, active, failure),
+            None => {
+                let _ = self
+                    .db
+                    .lock()
+                    .unwrap()
+                    .delete_segments(&download.url, &download.target_path());
+                self.finish(active, Ok(()));
+            }
+        }
+    }
+
+    /// Keeps tracking a download that still has transfers running, or finishes it if
+    /// there was nothing left to transfer.
+    fn keep_or_finish(&mut self, id: usize, active: Active) {
+        if active.running > 0 {
+            self.downloads.insert(id, active);
+        } else if matches!(active.plan, Plan::Segmented { .. }) {
+            self.segments_done(id, active);
+        } else {
+            self.finish(active, Ok(()));
+        }
+    }
+
+    /// Downloads the `damaged` pieces of a finished file again from the next source, keeping
+    /// the rest of it.
+    fn repair(&mut self, mut active: Active, damaged: &[usize]) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        active.repairs += 1;
+        active.source = (active.source + 1) % active.sources.len();
+        active.digest = None;
+        let download = &mut active.download;
+        download.etag = None;
+        download.last_modified = None;
+        let (Some(pieces), Some(size)) = (&download.pieces, download.size.or(active.size)) else {
+            return self.finish(active, Err("The file can't be repaired".to_string()));
+        };
+        self.options.output.println(&download.progress_bar, format!(
+            "{} of the {} pieces of {} don't match their digest, downloading them again from {}.",
+            damaged.len(),
+            pieces.digests.len(),
+            download.file_name,
+            active.sources[active.source]
+        ));
+        let segments = repair_segments(pieces, size, damaged);
+
+        match self.begin_segmented(id, &mut active, size, segments) {
+            Ok(_) => self.keep_or_finish(id, active),
+            Err(e) => self.finish(active, Err(e.to_string())),
+        }
+    }
+
+    /// The checksum the checksum list has for `file_name`, if there is a list.
+    fn look_up_checksum(&self, file_name: &str) -> Result<Option<Checksum>, String> {
+        let Some(checksum_list) = &self.options.checksum_list else {
+            return Ok(None);
+        };
+
+        match checksum_list.get(file_name)? {
+            Some(checksum) => Ok(Some(checksum.clone())),
+            None => Err(format!(
+                "{file_name} is not listed in {}",
+                checksum_list.source
+            )),
+        }
+    }
+
+    fn finish(&mut self, mut active: Active, result: Result<(), String>) {
+        active.download.duration = active
+            .download
+            .started
+            .map(|(started_at, _)| started_at.elapsed());
+        if active.download.status == DownloadStatus::Skipped {
+            if let Some(path) = active.claimed.take() {
+                self.claims.release(&path);
+            }
+            self.report_end(&active.download);
+            return self.finished.push(active.download);
+        }
+
+        let result = match result {
+            Ok(_) if active.look_up_checksum => {
+                let file_name = active
+                    .served_name
+                    .as_deref()
+                    .unwrap_or(&active.download.file_name);
+                self.look_up_checksum(file_name)
+                    .map(|checksum| active.download.checksum = checksum)
+            }
+            result => result,
+        };
+
+        // Damaged pieces are downloaded again, from the next mirror when there is one, before
+        // the file is given up on
+        let mut pieces_checked = Ok(());
+        if result.is_ok()
+            && let Some(pieces) = &active.download.pieces
+        {
+            let size = active.download.size.or(active.size);
+            match pieces.damaged(active.download.part_path()) {
+                Ok(damaged) if damaged.is_empty() => {}
+                Ok(damaged) if size.is_some() && active.repairs < active.sources.len() => {
+                    return self.repair(active, &damaged);
+                }
+                Ok(damaged) => {
+                    pieces_checked = Err(format!(
+                        "{} of {} pieces don't match their {} digest",
+                        damaged.len(),
+                        pieces.digests.len(),
+                        pieces.algorithm.name()
+                    ));
+                }
+                Err(e) => pieces_checked = Err(format!("Could not hash the downloaded file: {e}")),
+            }
+        }
+
+        if let Some(path) = active.claimed.take() {
+            self.claims.release(&path);
+        }
+
+        // Only a finished transfer is worth hashing
+        let download = &mut active.download;
+        let verified =
+            result.map(|_| pieces_checked.and_then(|_| download.verify(active.digest.take())));
+        match verified {
+            Ok(Err(e)) => download.fail_verification(e, &self.db),
+            result => {
+                let result = result.and_then(|_| download.move_into_place());
+                download.finish(result, &self.db)
+            }
+        }
+
+        self.report_end(&active.download);
+        self.finished.push(active.download);
+    }
+
+    /// Reports how a download ended.
+    fn report_end(&self, download: &Download) {
+        let output = self.options.output;
+        let url = &download.url;
+        let path = download.target_path().display().to_string();
+
+        match download.status {
+            DownloadStatus::Completed => {
+                if download.checksum.is_some()
+                    || download.pieces.is_some()
+                    || download.size.is_some()
+                {
+                    output.emit(Event::Verified {
+                        url,
+                        path: path.clone(),
+                        checksum: download.checksum.as_ref().map(Checksum::to_string),
+                    });
+                }
+                output.emit(Event::Completed {
+                    url,
+                    path,
+                    bytes: download.downloaded,
+                    duration: download.duration.unwrap_or_default().as_secs_f64(),
+                });
+            }
+            DownloadStatus::Skipped => {
+                output.println(
+                    &download.progress_bar,
+                    format!("{path} already exists, skipping {url}."),
+                );
+                output.emit(Event::Skipped { url, path });
+            }
+            _ => output.emit(Event::Failed {
+                url,
+                error: download.error.as_deref().unwrap_or("unknown error"),
+                verification: download.status == DownloadStatus::VerificationFailed,
+            }),
+        }
+    }
+}
+
+/// Lays out a `size` byte file as segments, the runs of good pieces already downloaded and
+/// the runs of `damaged` ones still to do.
+fn repair_segments(pieces: &Pieces, size: u64, damaged: &[usize]) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for index in 0..pieces.digests.len() {
+        let start = index as u64 * pieces.length;
+        if start >= size {
+            break;
+        }
+        let end = (start + pieces.length).min(size) - 1;
+        let good = !damaged.contains(&index);
+
+        match segments.last_mut() {
+            Some(last) if last.is_complete() == good => {
+                last.end = end;
+                if good {
+                    last.downloaded = end - last.start + 1;
+                }
+            }
+            _ => segments.push(Segment {
+                index: segments.len(),
+                start,
+                end,
+                downloaded: if good { end - start + 1 } else { 0 },
+            }),
+        }
+    }
+
+    segments
+}
+
+/// Sets aside `size` bytes for `file` so running out of space shows up now rather than
+/// partway through. File systems that can't do that just grow the file as it's written.
+fn preallocate(file: &File, size: u64) -> Result<(), Box<dyn Error>> {
+    match file.allocate(size) {
+        Err(e) if e.kind() == IoErrorKind::StorageFull => Err(format!(
+            "There isn't enough free space for the {} file",
+            HumanBytes(size)
+        )
+        .into()),
+        Err(e) if e.kind() != IoErrorKind::Unsupported => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum DownloadStatus {
+    Pending,
+    InProgress,
+    Completed,
+    Failed,
+    /// The file downloaded but didn't match its checksum.
+    VerificationFailed,
+    /// The file was already there and `--on-conflict skip` left it alone.
+    Skipped,
+}
+
+impl fmt::Display for DownloadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pending => "pending",
+            Self::InProgress => "in progress",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::VerificationFailed => "verification failed",
+            Self::Skipped => "skipped",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Algorithm;
+    use std::{env, process};
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    /// An empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("download_it-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Downloads `file_name` from `dir` into `dir/out`, which already has a file by that
+    /// name, checking it against a list with `digest` for it.
+    fn download_over_existing(dir: &Path, file_name: &str, digest: &str) -> Download {
+        fs::write(dir.join(file_name), b"hello").unwrap();
+        fs::write(dir.join("SHA256SUMS"), format!("{digest}  {file_name}\n")).unwrap();
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        fs::write(out.join(file_name), b"older").unwrap();
+
+        let sums = dir.join("SHA256SUMS");
+        let options = DownloadOptions {
+            checksum_list: Some(ChecksumList::load(sums.to_str().unwrap(), None, &[]).unwrap()),
+            on_conflict: ConflictPolicy::Rename,
+            ..Default::default()
+        };
+        let mut download = Download::new(
+            format!("file://{}", dir.join(file_name).display()),
+            None,
+            Some(out.to_string_lossy().to_string()),
+            None,
+        );
+        let db = Arc::new(Mutex::new(ResumeDb::in_memory().unwrap()));
+        let _ = download.execute(&options, db);
+
+        download
+    }
+
+    #[test]
+    fn checks_a_renamed_download_against_the_name_it_was_served_as() {
+        let dir = test_dir("renamed");
+        let download = download_over_existing(&dir, "foo.iso", HELLO_SHA256);
+
+        assert_eq!(download.status, DownloadStatus::Completed, "{download:?}");
+        assert_eq!(download.file_name, "foo (1).iso");
+        assert_eq!(download.checksum.unwrap().digest, HELLO_SHA256);
+        assert_eq!(fs::read(dir.join("out/foo (1).iso")).unwrap(), b"hello");
+        assert!(!dir.join("out/foo (1).iso.part").exists());
+
+        // The served name's digest is still the one it has to match
+        let dir = test_dir("renamed-mismatch");
+        let download = download_over_existing(&dir, "foo.iso", &"0".repeat(64));
+        assert_eq!(download.status, DownloadStatus::VerificationFailed);
+    }
+
+    /// Four 100 byte pieces, with the digests left out since only their count matters here.
+    fn pieces() -> Pieces {
+        Pieces {
+            algorithm: Algorithm::Sha256,
+            length: 100,
+            digests: vec![String::new(); 4],
         }
+    }
 
-        for t in threads {
-            t.join()
-                .expect("There was a thread that failed to rejoin the main thread!");
+    fn segment(index: usize, start: u64, end: u64, downloaded: u64) -> Segment {
+        Segment {
+            index,
+            start,
+            end,
+            downloaded,
         }
-    });
+    }
+
+    #[test]
+    fn repairs_runs_of_damaged_pieces() {
+        assert_eq!(
+            repair_segments(&pieces(), 400, &[1, 2]),
+            [
+                segment(0, 0, 99, 100),
+                segment(1, 100, 299, 0),
+                segment(2, 300, 399, 100),
+            ]
+        );
+        assert_eq!(
+            repair_segments(&pieces(), 400, &[0, 3]),
+            [
+                segment(0, 0, 99, 0),
+                segment(1, 100, 299, 200),
+                segment(2, 300, 399, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn repairs_a_file_with_every_piece_damaged() {
+        assert_eq!(
+            repair_segments(&pieces(), 400, &[0, 1, 2, 3]),
+            [segment(0, 0, 399, 0)]
+        );
+    }
 
-    Ok(())
+    #[test]
+    fn repairs_a_short_last_piece() {
+        assert_eq!(
+            repair_segments(&pieces(), 350, &[3]),
+            [segment(0, 0, 299, 300), segment(1, 300, 349, 0)]
+        );
+        assert_eq!(
+            repair_segments(&pieces(), 350, &[2]),
+            [
+                segment(0, 0, 199, 200),
+                segment(1, 200, 299, 0),
+                segment(2, 300, 349, 50),
+            ]
+        );
+    }
 }
@@ -1,82 +1,532 @@
 use crate::{
+    checksum::Checksum,
     db::ResumeDb,
-    download::{Download, DownloadStatus, download_multi, download_single},
+    download::{Download, DownloadOptions, DownloadStatus, Engine},
+    events::Event,
+    input::InputFile,
+    manifest::{EntryState, Manifest, SyncEntry},
+    metalink::{self, is_metalink},
+    summary::Summary,
 };
-use serde::{Deserialize, Serialize};
 use std::{
     error::Error,
+    fs,
     io::{Error as IoError, ErrorKind as IoErrorKind},
-    sync::mpsc::{Receiver, SyncSender, sync_channel},
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        mpsc::{TryRecvError, sync_channel},
+    },
+    thread,
 };
 
 #[derive(Debug)]
 pub struct DownloadManager {
-    db: ResumeDb,
-    queue: (SyncSender<Download>, Receiver<Download>),
+    db: Arc<Mutex<ResumeDb>>,
 }
 
 impl DownloadManager {
     pub fn new() -> Result<Self, Box<dyn Error>> {
         Ok(Self {
-            db: ResumeDb::new()?,
-            queue: sync_channel(10),
+            db: Arc::new(Mutex::new(ResumeDb::new()?)),
         })
     }
 
     pub fn download(
         &self,
-        urls: &Vec<String>,
-        cookie: Option<String>,
-        headers: Option<Vec<String>>,
+        url: String,
+        mirrors: Vec<String>,
+        file_path: Option<String>,
+        file_name: Option<String>,
+        checksum: Option<Checksum>,
+        options: &DownloadOptions,
+    ) -> Result<Summary, Box<dyn Error>> {
+        if is_metalink(&url) {
+            return self.download_metalink(&url, mirrors, file_path, file_name, checksum, options);
+        }
+
+        let mut download = Download::new(url, file_name, file_path, None);
+        download.mirrors = mirrors;
+        download.checksum = checksum;
+
+        Ok(self.run_one(download, options, false))
+    }
+
+    /// Downloads the files a Metalink document describes, each from its mirrors in turn. A
+    /// name, mirrors or checksum from the command line can only go with a single file.
+    fn download_metalink(
+        &self,
+        source: &str,
+        mirrors: Vec<String>,
+        file_path: Option<String>,
+        file_name: Option<String>,
+        checksum: Option<Checksum>,
+        options: &DownloadOptions,
+    ) -> Result<Summary, Box<dyn Error>> {
+        let files = metalink::load(source, options.cookie.as_deref(), &options.headers)?;
+        if files.len() > 1 && (file_name.is_some() || checksum.is_some() || !mirrors.is_empty()) {
+            return Err(Box::new(IoError::new(
+                IoErrorKind::InvalidInput,
+                format!(
+                    "{source} describes {} files, so a file name, mirror or checksum can't be given for it",
+                    files.len()
+                ),
+            )));
+        }
+
+        let mut downloads = files
+            .into_iter()
+            .map(|file| {
+                let mut urls = file.urls.into_iter();
+                let mut download = Download::new(
+                    urls.next().unwrap_or_default(),
+                    file_name.clone().or(Some(file.file_name)),
+                    file_path.clone(),
+                    None,
+                );
+                if let Some(dir) = file.dir {
+                    download.file_path = Path::new(&download.file_path)
+                        .join(dir)
+                        .to_string_lossy()
+                        .to_string();
+                }
+                download.mirrors = urls.chain(mirrors.iter().cloned()).collect();
+                download.checksum = checksum.clone().or(file.checksum);
+                download.size = file.size;
+                download.pieces = file.pieces;
+                download
+            })
+            .collect::<Vec<_>>();
+
+        if downloads.len() == 1 {
+            return Ok(self.run_one(downloads.remove(0), options, false));
+        }
+
+        self.run_all(downloads.into_iter(), options, false)
+    }
+
+    /// Downloads `urls` and then the ones in `input`, `options.jobs` at a time. The URLs in
+    /// `input` are read as the queue takes them, so the list can be any length.
+    pub fn download_multi(
+        &self,
+        urls: &[String],
         file_path: Option<Vec<String>>,
         file_name: Option<Vec<String>>,
-        multi: bool,
-    ) -> Result<(), Box<IoError>> {
-        if !multi {
-            if let Err(e) = download_single(
-                urls[0].clone(),
-                match file_path {
-                    Some(file_path) if file_path.len() > 0 => Some(file_path[0].clone()),
-                    _ => None,
-                },
-                match file_name {
-                    Some(file_name) if file_name.len() > 0 => Some(file_name[0].clone()),
-                    _ => None,
-                },
-                cookie,
-                headers,
-                None,
-            ) {
-                eprintln!("Download failed! You can try again, or try the `resume` subcommand.");
-                eprintln!("{e}");
+        checksums: Option<Vec<Checksum>>,
+        input: Option<InputFile>,
+        options: &DownloadOptions,
+    ) -> Result<Summary, Box<dyn Error>> {
+        if urls.is_empty() && input.is_none() {
+            return Err(Box::new(IoError::new(
+                IoErrorKind::InvalidInput,
+                "You must enter at least one URL or an --input-file.",
+            )));
+        }
+        // Checksums go with the URLs by position, so any other count would check the wrong files
+        if let Some(checksums) = &checksums
+            && checksums.len() != urls.len()
+        {
+            return Err(Box::new(IoError::new(
+                IoErrorKind::InvalidInput,
+                format!(
+                    "{} checksums were given for {} URLs, give one --checksum per URL. URLs from an --input-file take an indented checksum= line instead.",
+                    checksums.len(),
+                    urls.len()
+                ),
+            )));
+        }
+
+        let downloads = urls.iter().enumerate().map(|(idx, url)| {
+            let file_name = match &file_name {
+                Some(file_names) if file_names.len() == urls.len() => Some(file_names[idx].clone()),
+                _ => None,
             };
-        } else {
-            if let Err(e) = download_multi(&urls, file_path, file_name, cookie, headers) {
-                eprintln!(
-                    "One or more downloads failed! You can try again, or try the `resume` command."
-                );
-                eprintln!("{e}");
+            let file_path = match &file_path {
+                Some(file_path) if file_path.len() == 1 => Some(file_path[0].clone()),
+                Some(file_path) if file_path.len() == urls.len() => Some(file_path[idx].clone()),
+                _ => None,
             };
-        }
 
-        Ok(())
+            let checksum = checksums.as_ref().map(|checksums| checksums[idx].clone());
+
+            let mut download = Download::new(url.clone(), file_name, file_path, None);
+            download.checksum = checksum;
+            download
+        });
+
+        // A single directory goes for the listed URLs too, unless they give their own
+        let input_path = match &file_path {
+            Some(file_path) if file_path.len() == 1 => Some(file_path[0].clone()),
+            _ => None,
+        };
+        let downloads = downloads.chain(input.into_iter().flatten().map(move |entry| {
+            let file_path = entry.file_path.or_else(|| input_path.clone());
+            let mut download = Download::new(entry.url, entry.file_name, file_path, None);
+            download.mirrors = entry.mirrors;
+            download.checksum = entry.checksum;
+            download.headers = entry.headers;
+            download.cookie = entry.cookie;
+            // An entry that can't be downloaded as written fails without being tried
+            if let Some(error) = entry.error {
+                download.status = DownloadStatus::Failed;
+                download.error = Some(error);
+            }
+            download
+        }));
+
+        self.run_all(downloads, options, false)
     }
 
     pub fn resume_download(
         &self,
-        urls: &Vec<String>,
-        cookie: Option<String>,
-        headers: Option<Vec<String>>,
+        urls: &[String],
+        options: &DownloadOptions,
         multi: bool,
-    ) -> Result<(), Box<dyn Error>> {
-        if !multi {
-            let url = urls[0].clone();
+    ) -> Result<Summary, Box<dyn Error>> {
+        if urls.is_empty() {
+            return Err(Box::new(IoError::new(
+                IoErrorKind::InvalidInput,
+                "You must enter at least one URL to resume.",
+            )));
+        }
+
+        // Only look up the first URL unless multiple resumes were asked for
+        let urls = if multi { urls } else { &urls[..1] };
+
+        let mut downloads = Vec::new();
+        let mut finished = 0;
+        let mut unknown = Vec::new();
+        for url in urls {
+            let saved = self.db.lock().unwrap().get_resumes(url)?;
+            if saved.is_empty() {
+                unknown.push(url);
+            }
+
+            // A URL saved to more than one place is resumed in each of them
+            for download in saved {
+                if download.status == DownloadStatus::Completed {
+                    options.output.status(format!(
+                        "{url} has already finished downloading to {}, skipping.",
+                        download.target_path().display()
+                    ));
+                    finished += 1;
+                } else {
+                    downloads.push(download);
+                }
+            }
+        }
+
+        // Nothing that was asked for could ever be resumed, most likely a mistyped URL
+        if downloads.is_empty() && finished == 0 {
+            return Err(Box::new(IoError::new(
+                IoErrorKind::InvalidInput,
+                format!(
+                    "There is no interrupted download saved for {}",
+                    unknown
+                        .iter()
+                        .map(|url| url.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )));
+        }
+
+        let mut summary = if downloads.is_empty() {
+            Summary::default()
+        } else if !multi && downloads.len() == 1 {
+            self.run_one(downloads.remove(0), options, true)
+        } else {
+            self.run_all(downloads.into_iter(), options, true)?
+        };
+
+        // The rest still count against the run, so it doesn't look like everything resumed
+        summary.downloads.extend(unknown.into_iter().map(|url| {
+            let mut download = Download::new(url.clone(), None, Some(String::new()), None);
+            download.status = DownloadStatus::Failed;
+            download.error = Some("There is no interrupted download saved for it".to_string());
+            download
+        }));
+
+        Ok(summary)
+    }
+
+    /// Brings the `entries` of `manifest` up to date. Reports how each one compares to the
+    /// manifest, downloads the ones that are missing or changed, and with `prune` removes the
+    /// files it downloaded for the manifest before that the manifest no longer lists. With
+    /// `dry_run` nothing is downloaded or removed. Returns the files it downloaded.
+    pub fn sync(
+        &self,
+        manifest: &Manifest,
+        entries: &[SyncEntry],
+        options: &DownloadOptions,
+        prune: bool,
+        dry_run: bool,
+    ) -> Result<Summary, Box<dyn Error>> {
+        if prune {
+            manifest
+                .check_prunable()
+                .map_err(|e| IoError::new(IoErrorKind::InvalidInput, e))?;
+        }
+
+        // Servers are asked about `jobs` entries at a time, and the database is only locked
+        // to look up what was saved about each one
+        let mut pending = Vec::new();
+        for chunk in entries.chunks(options.jobs.max(1)) {
+            let states = thread::scope(|s| {
+                chunk
+                    .iter()
+                    .map(|entry| {
+                        s.spawn(|| {
+                            entry.state(options.cookie.as_deref(), &options.headers, &self.db)
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .map(|checking| {
+                        checking.join().unwrap_or_else(|_| {
+                            EntryState::Unchecked("checking it stopped with an error".to_string())
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+            for (entry, state) in chunk.iter().zip(states) {
+                options
+                    .output
+                    .status(format!("{}: {state}", entry.target_path().display()));
+                if state.needs_download() {
+                    pending.push(entry);
+                }
+            }
+        }
+        let outdated = pending.len();
+
+        let mut summary = Summary::default();
+        if !dry_run {
+            let downloads = pending.iter().map(|entry| {
+                let mut download = Download::new(
+                    entry.url.clone(),
+                    Some(entry.file_name.clone()),
+                    Some(entry.file_path.clone()),
+                    None,
+                );
+                download.mirrors = entry.mirrors.clone();
+                download.checksum = entry.checksum.clone();
+                download.headers = entry.headers.clone();
+                download
+            });
+            summary = Summary::new(self.run_queue(downloads, options, false)?);
+        }
+        let failed = summary.failed();
+
+        // Only what was downloaded for the manifest is ever pruned
+        let db = self.db.lock().unwrap();
+        for download in &summary.downloads {
+            if download.status == DownloadStatus::Completed
+                && let Ok(path) = fs::canonicalize(download.target_path())
+                && let Err(e) = db.create_synced_file(&manifest.key(), &path.to_string_lossy())
+            {
+                eprintln!("Could not record {} as synced: {e}", path.display());
+            }
+        }
+
+        if prune {
+            let synced = db.get_synced_files(&manifest.key())?;
+            for path in manifest.unlisted_files(entries, &synced) {
+                if dry_run {
+                    options
+                        .output
+                        .status(format!("Would remove {}", path.display()));
+                    continue;
+                }
+
+                match fs::remove_file(&path) {
+                    Ok(_) => {
+                        options.output.status(format!("Removed {}", path.display()));
+                        db.delete_synced_file(&manifest.key(), &path.to_string_lossy())?;
+                    }
+                    Err(e) => eprintln!("Could not remove {}: {e}", path.display()),
+                }
+            }
+        }
+
+        if dry_run {
+            options.output.status(format!(
+                "{} of {} files are up to date, {outdated} would be downloaded.",
+                entries.len() - outdated,
+                entries.len()
+            ));
+        } else if failed > 0 {
+            eprintln!(
+                "{failed} of {outdated} files could not be downloaded! Run it again to retry them."
+            );
+        } else {
+            options.output.status(format!(
+                "All {} files are up to date, {outdated} of them had to be downloaded.",
+                entries.len()
+            ));
+        }
+
+        Ok(summary)
+    }
+
+    /// Runs one download on its own engine.
+    fn run_one(&self, mut download: Download, options: &DownloadOptions, resume: bool) -> Summary {
+        download.attach_progress_bar(&options.multi_progress);
+        options.output.emit(Event::Queued {
+            url: &download.url,
+            path: download.target_path().display().to_string(),
+        });
+        let result = if resume {
+            download.execute_resume(options, Arc::clone(&self.db))
+        } else {
+            download.execute(options, Arc::clone(&self.db))
+        };
+
+        if let Err(e) = result {
+            // The engine failing to run isn't recorded in the download itself
+            if download.error.is_none() {
+                download.status = DownloadStatus::Failed;
+                download.error = Some(e.to_string());
+            }
 
-            let mut download = self.db.get_resume(&url)?;
+            if resume {
+                eprintln!("Resume failed! You can try the `resume` subcommand again.");
+            } else {
+                eprintln!("Download failed! You can try again, or try the `resume` subcommand.");
+            }
+        }
+
+        Summary::new(vec![download])
+    }
+
+    /// Runs `downloads` through the queue, saying how to try again if any of them failed.
+    fn run_all(
+        &self,
+        downloads: impl Iterator<Item = Download> + Send,
+        options: &DownloadOptions,
+        resume: bool,
+    ) -> Result<Summary, Box<dyn Error>> {
+        let summary = match self.run_queue(downloads, options, resume) {
+            Ok(finished) => Summary::new(finished),
+            Err(e) if resume => {
+                return Err(format!("The resumes stopped with an error: {e}").into());
+            }
+            Err(e) => return Err(format!("The downloads stopped with an error: {e}").into()),
+        };
 
-            download.execute()?;
+        if summary.failed() > 0 && resume {
+            eprintln!("One or more resumes failed! You can try the `resume` subcommand again.");
+        } else if summary.failed() > 0 {
+            eprintln!(
+                "One or more downloads failed! You can try again, or try the `resume` command."
+            );
         }
 
-        Ok(())
+        Ok(summary)
+    }
+
+    /// Feeds `downloads` through the queue into one `Engine`, keeping `options.jobs` of them
+    /// running at once while the rest wait as queued. Returns every download as it ended.
+    fn run_queue(
+        &self,
+        downloads: impl Iterator<Item = Download> + Send,
+        options: &DownloadOptions,
+        resume: bool,
+    ) -> Result<Vec<Download>, Box<dyn Error>> {
+        let jobs = options.jobs.max(1);
+        // Downloads waiting for the engine to take them. `None` marks the end of the queue.
+        let (sender, receiver) = sync_channel::<Option<Download>>(10);
+
+        let mut engine = Engine::new(options, Arc::clone(&self.db))
+            .map_err(|e| format!("Could not start the downloads: {e}"))?;
+
+        thread::scope(|s| {
+            // The queue is bounded, so this waits for the engine to take more when it's full.
+            // The sender goes with it, so the queue ends even if making a download panics.
+            let multi_progress = &options.multi_progress;
+            let feeder = s.spawn(move || {
+                for mut download in downloads {
+                    download.attach_progress_bar(multi_progress);
+                    download
+                        .progress_bar
+                        .set_message(format!("{} (queued)", download.file_name));
+                    options.output.emit(Event::Queued {
+                        url: &download.url,
+                        path: download.target_path().display().to_string(),
+                    });
+
+                    if sender.send(Some(download)).is_err() {
+                        return;
+                    }
+                }
+
+                let _ = sender.send(None);
+            });
+
+            let mut done = Vec::new();
+            let mut queue_done = false;
+            loop {
+                // Top the engine back up, only blocking on the queue when nothing is running
+                while !queue_done && engine.active() < jobs {
+                    let next = if engine.is_idle() {
+                        receiver.recv().ok()
+                    } else {
+                        match receiver.try_recv() {
+                            Ok(next) => Some(next),
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => None,
+                        }
+                    };
+
+                    match next {
+                        Some(Some(download)) => engine.add(download, resume),
+                        _ => queue_done = true,
+                    }
+                }
+
+                if queue_done && engine.is_idle() {
+                    break;
+                }
+
+                match engine.step() {
+                    Ok(finished) => {
+                        for download in finished {
+                            if !matches!(
+                                download.status,
+                                DownloadStatus::Completed | DownloadStatus::Skipped
+                            ) {
+                                options.output.println(
+                                    &download.progress_bar,
+                                    format!(
+                                        "{} failed to download: {}",
+                                        download.url,
+                                        download.error.as_deref().unwrap_or("unknown error")
+                                    ),
+                                );
+                            }
+                            done.push(download);
+                        }
+                    }
+                    Err(e) => {
+                        // The feeder's next send fails, so it stops instead of reading the
+                        // rest of the list
+                        drop(receiver);
+                        return Err(e);
+                    }
+                }
+            }
+
+            feeder.join().map_err(|panic| {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".to_string());
+                format!("The queue of downloads stopped: {reason}")
+            })?;
+
+            Ok(done)
+        })
     }
 }
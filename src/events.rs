@@ -0,0 +1,224 @@
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often a running download reports its progress as a JSON event.
+pub const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a running download reports its progress as a plain line, when there are no
+/// progress bars.
+pub const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How a run shows what it's doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Output {
+    /// Progress bars, or plain lines when stderr isn't a terminal to draw them on.
+    #[default]
+    Human,
+    /// One JSON event per line on standard output, for other programs to read.
+    Json,
+}
+
+impl Output {
+    /// Whether progress bars are drawn.
+    pub fn bars(self) -> bool {
+        self == Self::Human && io::stderr().is_terminal()
+    }
+
+    /// How often running downloads report their progress, `None` when the bars show it.
+    pub fn progress_interval(self) -> Option<Duration> {
+        match self {
+            Self::Json => Some(JSON_PROGRESS_INTERVAL),
+            Self::Human if self.bars() => None,
+            Self::Human => Some(PLAIN_PROGRESS_INTERVAL),
+        }
+    }
+
+    /// Where the progress bars of a run are drawn, which is nowhere when `bars` is false.
+    pub fn multi_progress(self) -> MultiProgress {
+        if self.bars() {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        }
+    }
+
+    /// Shows a message about a download above the progress bars, or on a line of its own
+    /// on stderr when they aren't drawn, where `ProgressBar::println` would show nothing.
+    pub fn println(self, progress_bar: &ProgressBar, message: impl AsRef<str>) {
+        if self.bars() {
+            progress_bar.println(message);
+        } else {
+            eprintln!("{}", message.as_ref());
+        }
+    }
+
+    /// Shows a message about the run as a whole above its progress bars, or on a line of its
+    /// own on stderr when they aren't drawn.
+    pub fn notice(self, multi_progress: &MultiProgress, message: impl AsRef<str>) {
+        if self.bars() {
+            let _ = multi_progress.println(message);
+        } else {
+            eprintln!("{}", message.as_ref());
+        }
+    }
+
+    /// Shows a result of the run, like how a file compares to its manifest. Standard output
+    /// is left to the events when they are JSON.
+    pub fn status(self, message: impl AsRef<str>) {
+        match self {
+            Self::Human => println!("{}", message.as_ref()),
+            Self::Json => eprintln!("{}", message.as_ref()),
+        }
+    }
+
+    /// Reports `event` as a JSON line, or as a plain line when there are no progress bars
+    /// to show it.
+    pub fn emit(self, event: Event) {
+        match self {
+            Self::Json => match serde_json::to_string(&event) {
+                Ok(json) => println!("{json}"),
+                Err(e) => eprintln!("Could not write an event as JSON: {e}"),
+            },
+            Self::Human if self.bars() => {}
+            Self::Human => {
+                if let Some(line) = event.plain_line() {
+                    eprintln!("{line}");
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Unknown output format `{value}`, expected human or json"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Human => "human",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// Something that happened to a download. Each is written as one JSON object with the kind
+/// of event in its `event` field.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The download is waiting for its turn.
+    Queued { url: &'a str, path: String },
+    /// A transfer started, `resumed_from` bytes into the file.
+    Started {
+        url: &'a str,
+        path: String,
+        resumed_from: u64,
+    },
+    Progress {
+        url: &'a str,
+        bytes: u64,
+        total: Option<u64>,
+        /// Bytes per second.
+        speed: u64,
+    },
+    /// The download failed and will be tried again after `delay` seconds, from `next_url`.
+    Retry {
+        url: &'a str,
+        next_url: &'a str,
+        attempt: u32,
+        retries: u32,
+        delay: f64,
+        error: &'a str,
+    },
+    /// The file matched everything it was checked against.
+    Verified {
+        url: &'a str,
+        path: String,
+        checksum: Option<String>,
+    },
+    Completed {
+        url: &'a str,
+        path: String,
+        bytes: u64,
+        /// How long the download took in this run, in seconds.
+        duration: f64,
+    },
+    /// The file was already there and was left alone.
+    Skipped { url: &'a str, path: String },
+    Failed {
+        url: &'a str,
+        error: &'a str,
+        /// Whether the file downloaded but didn't pass verification.
+        verification: bool,
+    },
+}
+
+impl Event<'_> {
+    /// The line to log for the event when there are no progress bars. Queued downloads
+    /// aren't worth a line each, and the others print their own message.
+    fn plain_line(&self) -> Option<String> {
+        match self {
+            Self::Queued { .. }
+            | Self::Retry { .. }
+            | Self::Skipped { .. }
+            | Self::Failed { .. } => None,
+            Self::Started {
+                url,
+                path,
+                resumed_from: 0,
+            } => Some(format!("Downloading {url} to {path}")),
+            Self::Started {
+                url,
+                path,
+                resumed_from,
+            } => Some(format!(
+                "Resuming {url} to {path} from {}",
+                HumanBytes(*resumed_from)
+            )),
+            Self::Progress {
+                url,
+                bytes,
+                total,
+                speed,
+            } => Some(match total {
+                Some(total) => format!(
+                    "{url}: {} of {} ({}/s)",
+                    HumanBytes(*bytes),
+                    HumanBytes(*total),
+                    HumanBytes(*speed)
+                ),
+                None => format!("{url}: {} ({}/s)", HumanBytes(*bytes), HumanBytes(*speed)),
+            }),
+            Self::Verified {
+                path,
+                checksum: Some(checksum),
+                ..
+            } => Some(format!("Verified {path} against {checksum}")),
+            Self::Verified { path, .. } => Some(format!("Verified {path}")),
+            Self::Completed {
+                path,
+                bytes,
+                duration,
+                ..
+            } => Some(format!(
+                "Downloaded {path} ({} in {duration:.1}s)",
+                HumanBytes(*bytes)
+            )),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    checksum::Checksum,
+    naming::{DEFAULT_FILE_NAME, file_name_from_response, file_name_from_url},
+    transfer::probe,
+};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::error::Error;
+
+/// What `download_it` would do with a URL, worked out the same way a download does before it
+/// starts.
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    /// The name the file would be saved as when no name is given.
+    pub file_name: String,
+    /// The URL the file comes from once redirects have been followed.
+    pub final_url: String,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    /// Whether the server sends byte ranges, so the file can be resumed and split into segments.
+    pub ranges: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Digests the server sent for the file.
+    pub digests: Vec<Checksum>,
+}
+
+impl FileInfo {
+    pub fn fetch(
+        url: &str,
+        cookie: Option<&str>,
+        headers: &[String],
+    ) -> Result<Self, Box<dyn Error>> {
+        let (response, final_url) = probe(url, cookie, headers)?;
+
+        let file_name = file_name_from_response(&response, &final_url)
+            .or_else(|| file_name_from_url(url))
+            .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
+
+        Ok(Self {
+            file_name,
+            final_url,
+            size: response.file_size(),
+            content_type: response.content_type.clone(),
+            ranges: response.supports_ranges(),
+            etag: response.etag.clone(),
+            last_modified: response.last_modified.clone(),
+            digests: response.digests,
+        })
+    }
+
+    /// Prints the info for a person to read.
+    pub fn print(&self) {
+        let unknown = || "unknown".to_string();
+
+        println!("File name:     {}", self.file_name);
+        println!("Final URL:     {}", self.final_url);
+        println!(
+            "Size:          {}",
+            self.size.map_or_else(unknown, |size| format!(
+                "{} ({size} bytes)",
+                HumanBytes(size)
+            ))
+        );
+        println!(
+            "Content type:  {}",
+            self.content_type.clone().unwrap_or_else(unknown)
+        );
+        println!(
+            "Ranges:        {}",
+            if self.ranges {
+                "yes, it can be resumed and split into segments"
+            } else {
+                "no, it can only be downloaded from the start over one connection"
+            }
+        );
+        println!(
+            "ETag:          {}",
+            self.etag.clone().unwrap_or_else(unknown)
+        );
+        println!(
+            "Last modified: {}",
+            self.last_modified.clone().unwrap_or_else(unknown)
+        );
+
+        match self.digests.as_slice() {
+            [] => println!("Digests:       none"),
+            [first, rest @ ..] => {
+                println!("Digests:       {first}");
+                for digest in rest {
+                    println!("               {digest}");
+                }
+            }
+        }
+    }
+}
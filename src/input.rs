@@ -0,0 +1,248 @@
+use crate::{checksum::Checksum, naming::check_file_name};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+/// A list of URLs to download, read a line at a time so even a very long list never has to be
+/// in memory at once. Each file is on a line of its own, with any mirrors after its URL
+/// separated by tabs, and can be followed by indented `key=value` lines that apply to it alone:
+///
+/// ```text
+/// https://example.com/file.iso
+///   out=debian.iso
+///   dir=/srv/isos
+///   header=Authorization: Bearer abc
+///   checksum=sha256:<hex>
+///   cookie=cookies.txt
+/// ```
+///
+/// Blank lines and lines starting with `#` are skipped.
+pub struct InputFile {
+    /// Where the list comes from, for error messages.
+    source: String,
+    reader: Box<dyn BufRead + Send>,
+    line_number: usize,
+    /// A URL line read while looking for the options of the one before it.
+    next_url: Option<(usize, String)>,
+    /// Why the list stopped early, until it's reported.
+    read_error: Option<String>,
+    /// Set once the list can't be read any further.
+    done: bool,
+}
+
+/// A URL from an input file with the options given for it.
+#[derive(Debug, Default)]
+pub struct InputEntry {
+    pub url: String,
+    /// Other URLs with the same file, given after the first one on its line.
+    pub mirrors: Vec<String>,
+    pub file_name: Option<String>,
+    pub file_path: Option<String>,
+    pub headers: Vec<String>,
+    pub checksum: Option<Checksum>,
+    pub cookie: Option<String>,
+    /// Why the entry can't be downloaded, like a bad option line under it. Reported as a
+    /// failed download rather than dropped, so the run doesn't look like it got everything.
+    pub error: Option<String>,
+}
+
+impl InputEntry {
+    /// Applies one `key=value` option line to the entry.
+    fn set(&mut self, option: &str) -> Result<(), String> {
+        let Some((key, value)) = option.split_once('=') else {
+            return Err(format!("`{option}` is not a key=value option"));
+        };
+        let value = value.trim();
+
+        match key.trim() {
+            "out" => self.file_name = Some(check_file_name(value)?),
+            "dir" => self.file_path = Some(value.to_string()),
+            "header" => self.headers.push(value.to_string()),
+            "checksum" => self.checksum = Some(value.parse()?),
+            "cookie" => self.cookie = Some(value.to_string()),
+            key => {
+                return Err(format!(
+                    "unknown option `{key}`, expected out, dir, header, checksum or cookie"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl InputFile {
+    /// Opens the list at `path`, or reads it from standard input for `-`.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let (source, reader): (String, Box<dyn BufRead + Send>) = if path == "-" {
+            (
+                "standard input".to_string(),
+                Box::new(BufReader::new(io::stdin())),
+            )
+        } else {
+            (
+                path.to_string(),
+                Box::new(BufReader::new(File::open(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Could not open {path}: {e}"))
+                })?)),
+            )
+        };
+
+        Ok(Self {
+            source,
+            reader,
+            line_number: 0,
+            next_url: None,
+            read_error: None,
+            done: false,
+        })
+    }
+
+    /// Reads the next line that isn't blank or a comment, keeping its indentation.
+    fn next_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        while !self.done {
+            line.clear();
+            self.line_number += 1;
+
+            match self.reader.read_line(&mut line) {
+                Ok(0) => self.done = true,
+                Ok(_) => {}
+                Err(e) => {
+                    self.done = true;
+                    self.read_error = Some(format!(
+                        "Could not read line {} of {}, so the rest of it was skipped: {e}",
+                        self.line_number, self.source
+                    ));
+                }
+            }
+
+            let trimmed = line.trim();
+            if !self.done && !trimmed.is_empty() && !trimmed.starts_with('#') {
+                return Some(line.trim_end().to_string());
+            }
+        }
+
+        None
+    }
+}
+
+impl Iterator for InputFile {
+    type Item = InputEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (url_line, url) = match self.next_url.take() {
+            Some(next_url) => next_url,
+            None => match self.next_line() {
+                Some(line) => (self.line_number, line.trim().to_string()),
+                // A list cut short by a read error ends with an entry saying so
+                None => {
+                    return self.read_error.take().map(|error| InputEntry {
+                        url: self.source.clone(),
+                        error: Some(error),
+                        ..Default::default()
+                    });
+                }
+            },
+        };
+
+        let mut urls = url.split('\t').map(str::trim).filter(|url| !url.is_empty());
+        let mut entry = InputEntry {
+            url: urls.next().unwrap_or_default().to_string(),
+            mirrors: urls.map(str::to_string).collect(),
+            ..Default::default()
+        };
+        while let Some(line) = self.next_line() {
+            if !line.starts_with([' ', '\t']) {
+                self.next_url = Some((self.line_number, line.trim().to_string()));
+                break;
+            }
+
+            // Downloading it anyway could save it under the wrong name or leave it unchecked
+            if let Err(e) = entry.set(line.trim())
+                && entry.error.is_none()
+            {
+                entry.error = Some(format!(
+                    "line {} of {}, under the URL on line {url_line}: {e}",
+                    self.line_number, self.source
+                ));
+            }
+        }
+        // Its options may not all have been read
+        if entry.error.is_none() {
+            entry.error = self.read_error.take();
+        }
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn list(text: impl Into<Vec<u8>>) -> InputFile {
+        InputFile {
+            source: "list.txt".to_string(),
+            reader: Box::new(Cursor::new(text.into())),
+            line_number: 0,
+            next_url: None,
+            read_error: None,
+            done: false,
+        }
+    }
+
+    #[test]
+    fn reads_urls_with_their_mirrors_and_options() {
+        let entries = list(
+            b"# files\n\nhttps://a.example/1.iso\thttps://b.example/1.iso\n  out=one.iso\n\tdir=/srv\n  header=X-A: 1\n  header=X-B: 2\n  cookie=jar.txt\nhttps://a.example/2.iso\n",
+        )
+        .collect::<Vec<_>>();
+
+        assert_eq!(entries.len(), 2);
+        let first = &entries[0];
+        assert_eq!(first.url, "https://a.example/1.iso");
+        assert_eq!(first.mirrors, ["https://b.example/1.iso"]);
+        assert_eq!(first.file_name.as_deref(), Some("one.iso"));
+        assert_eq!(first.file_path.as_deref(), Some("/srv"));
+        assert_eq!(first.headers, ["X-A: 1", "X-B: 2"]);
+        assert_eq!(first.cookie.as_deref(), Some("jar.txt"));
+        assert_eq!(first.error, None);
+        assert_eq!(entries[1].url, "https://a.example/2.iso");
+        assert_eq!(entries[1].error, None);
+    }
+
+    #[test]
+    fn keeps_an_entry_with_a_bad_option_as_an_error() {
+        for option in ["  size=10", "  out", "  checksum=sha256:abc", "  out=.."] {
+            let text = format!("https://a.example/1.iso\n{option}\nhttps://a.example/2.iso\n");
+            let entries = list(text).collect::<Vec<_>>();
+
+            assert_eq!(entries.len(), 2, "{option}");
+            assert_eq!(entries[0].url, "https://a.example/1.iso");
+            let error = entries[0].error.as_deref().unwrap();
+            assert!(error.starts_with("line 2 of list.txt"), "{error}");
+            assert_eq!(entries[1].error, None, "{option}");
+        }
+    }
+
+    #[test]
+    fn reports_a_list_that_cannot_be_read() {
+        let entries = list(b"\xff\xfe\nhttps://a.example/1.iso\n").collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, "list.txt");
+        let error = entries[0].error.as_deref().unwrap();
+        assert!(
+            error.starts_with("Could not read line 1 of list.txt"),
+            "{error}"
+        );
+
+        // The entry being read when it stopped may be missing some of its options
+        let entries =
+            list(b"https://a.example/1.iso\n  out=one.iso\n  dir=\xff\n").collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, "https://a.example/1.iso");
+        let error = entries[0].error.as_deref().unwrap();
+        assert!(error.contains("the rest of it was skipped"), "{error}");
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{
+    checksum::{Algorithm, Checksum, digest_file},
+    manifest::SyncEntry,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs,
+    io::Error as IoError,
+    path::{Path, PathBuf},
+};
+
+/// The name of the lock file, kept next to the manifest.
+pub const LOCK_FILE_NAME: &str = "download_it.lock";
+
+const LOCK_FILE_VERSION: u32 = 1;
+
+/// Exactly which file each manifest entry resolved to when `lock` was run, so `fetch --locked`
+/// can refuse anything else.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Lockfile {
+    version: u32,
+    #[serde(default)]
+    files: Vec<LockedFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LockedFile {
+    /// The URL in the manifest.
+    url: String,
+    /// Where the file is saved, relative to the manifest.
+    path: String,
+    /// The URL the file came from once redirects were followed.
+    final_url: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    sha256: String,
+}
+
+impl Lockfile {
+    /// Records the downloaded file of every entry. `base` is the manifest's directory.
+    pub fn resolve(
+        entries: &[SyncEntry],
+        base: &Path,
+        cookie: Option<&str>,
+        headers: &[String],
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut files = Vec::new();
+
+        for entry in entries {
+            let target = entry.target_path();
+            let metadata = fs::metadata(&target).map_err(|e| {
+                format!(
+                    "{} could not be locked, it hasn't been downloaded: {e}",
+                    target.display()
+                )
+            })?;
+            let sha256 = digest_file(Algorithm::Sha256, &target)?;
+
+            let (response, final_url) = entry
+                .probe(cookie, headers)
+                .map_err(|e| format!("{} could not be locked: {e}", entry.url))?;
+
+            files.push(LockedFile {
+                url: entry.url.clone(),
+                path: relative_path(&target, base),
+                final_url,
+                size: metadata.len(),
+                etag: response.etag,
+                sha256,
+            });
+        }
+
+        Ok(Self {
+            version: LOCK_FILE_VERSION,
+            files,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            IoError::new(
+                e.kind(),
+                format!(
+                    "Could not read {}, run `download_it lock` to make it: {e}",
+                    path.display()
+                ),
+            )
+        })?;
+        let lockfile: Self = toml::from_str(&text)
+            .map_err(|e| format!("{} is not a valid lock file: {e}", path.display()))?;
+
+        if lockfile.version != LOCK_FILE_VERSION {
+            return Err(format!(
+                "{} is version {} of the lock file, but only version {LOCK_FILE_VERSION} can be read",
+                path.display(),
+                lockfile.version
+            )
+            .into());
+        }
+
+        Ok(lockfile)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let text = format!(
+            "# Written by `download_it lock`, don't edit it by hand.\n{}",
+            toml::to_string(self)?
+        );
+        fs::write(path, text)?;
+
+        Ok(())
+    }
+
+    /// Pins every entry to the SHA-256 it was locked with. Entries that aren't in the lock
+    /// file, or whose checksum in the manifest has changed since, mean it's out of date.
+    pub fn apply(&self, entries: &mut [SyncEntry], base: &Path) -> Result<(), String> {
+        for entry in entries {
+            let path = relative_path(&entry.target_path(), base);
+            let Some(locked) = self
+                .files
+                .iter()
+                .find(|locked| locked.url == entry.url && locked.path == path)
+            else {
+                return Err(format!(
+                    "{} isn't in {LOCK_FILE_NAME}, run `download_it lock` to update it",
+                    entry.url
+                ));
+            };
+
+            let checksum = Checksum {
+                algorithm: Algorithm::Sha256,
+                digest: locked.sha256.clone(),
+            };
+            if let Some(expected) = &entry.checksum
+                && expected.algorithm == Algorithm::Sha256
+                && !expected.matches(&locked.sha256)
+            {
+                return Err(format!(
+                    "The manifest's checksum for {} differs from {LOCK_FILE_NAME}, run `download_it lock` to update it",
+                    entry.url
+                ));
+            }
+            entry.checksum = Some(checksum);
+        }
+
+        Ok(())
+    }
+}
+
+/// `path` relative to `base` with `/` separators, so the lock file reads the same everywhere.
+fn relative_path(path: &Path, base: &Path) -> String {
+    path.strip_prefix(base)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const OTHER_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn entry(url: &str, checksum: Option<&str>) -> SyncEntry {
+        SyncEntry {
+            url: url.to_string(),
+            mirrors: Vec::new(),
+            file_path: "tools/bin".to_string(),
+            file_name: "zig.tar.xz".to_string(),
+            checksum: checksum.map(|checksum| checksum.parse().unwrap()),
+            headers: Vec::new(),
+        }
+    }
+
+    fn lockfile() -> Lockfile {
+        toml::from_str(&format!(
+            r#"
+            version = 1
+
+            [[files]]
+            url = "https://example.com/zig.tar.xz"
+            path = "bin/zig.tar.xz"
+            final_url = "https://cdn.example.com/zig.tar.xz"
+            size = 10
+            sha256 = "{SHA256}"
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn pins_entries_to_their_locked_sha256() {
+        let mut entries = [entry("https://example.com/zig.tar.xz", None)];
+        lockfile().apply(&mut entries, Path::new("tools")).unwrap();
+
+        assert_eq!(
+            entries[0].checksum,
+            Some(format!("sha256:{SHA256}").parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn refuses_entries_missing_from_the_lock_file() {
+        // Another URL, or the same one saved somewhere else
+        let mut entries = [entry("https://example.com/other.tar.xz", None)];
+        let error = lockfile()
+            .apply(&mut entries, Path::new("tools"))
+            .unwrap_err();
+        assert!(error.contains("isn't in download_it.lock"), "{error}");
+
+        let mut entries = [entry("https://example.com/zig.tar.xz", None)];
+        assert!(lockfile().apply(&mut entries, Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn refuses_a_manifest_checksum_that_changed_since_locking() {
+        let mut entries = [entry(
+            "https://example.com/zig.tar.xz",
+            Some(&format!("sha256:{OTHER_SHA256}")),
+        )];
+        let error = lockfile()
+            .apply(&mut entries, Path::new("tools"))
+            .unwrap_err();
+        assert!(error.contains("differs from download_it.lock"), "{error}");
+
+        let mut entries = [entry(
+            "https://example.com/zig.tar.xz",
+            Some(&format!("sha256:{}", SHA256.to_ascii_uppercase())),
+        )];
+        assert!(lockfile().apply(&mut entries, Path::new("tools")).is_ok());
+    }
+
+    #[test]
+    fn writes_paths_relative_to_the_manifest() {
+        assert_eq!(
+            relative_path(Path::new("tools/bin/zig.tar.xz"), Path::new("tools")),
+            "bin/zig.tar.xz"
+        );
+        assert_eq!(
+            relative_path(Path::new("./zig.tar.xz"), Path::new(".")),
+            "zig.tar.xz"
+        );
+    }
+}
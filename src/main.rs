@@ -1,40 +1,95 @@
+mod checksum;
 mod cli;
+mod conflict;
 pub mod db;
 mod download;
 mod download_manager;
+mod events;
+mod info;
+mod input;
+mod lockfile;
+mod manifest;
+mod metalink;
+mod naming;
+mod summary;
+mod transfer;
 
-use std::fmt::Debug;
-
-use crate::download_manager::DownloadManager;
+use crate::{
+    checksum::ChecksumList,
+    conflict::ConflictPolicy,
+    download::DownloadOptions,
+    download_manager::DownloadManager,
+    info::FileInfo,
+    input::InputFile,
+    lockfile::Lockfile,
+    manifest::Manifest,
+    summary::{Outcome, Summary},
+    transfer::RateLimiter,
+};
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, parse_rate};
+use std::error::Error;
+use std::io::{self, ErrorKind as IoErrorKind, IsTerminal};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> ExitCode {
     let args = Cli::parse();
-    let manager = DownloadManager::new()?;
 
-    match args.commands {
+    match run(args) {
+        Ok(summary) => {
+            summary.print();
+            summary.outcome().exit_code()
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            // Mistakes on the command line that the argument parser can't catch
+            let usage_error = e
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == IoErrorKind::InvalidInput);
+            if usage_error {
+                Outcome::UsageError.exit_code()
+            } else {
+                Outcome::Failure.exit_code()
+            }
+        }
+    }
+}
+
+/// Runs the command, returning the files it downloaded.
+fn run(args: Cli) -> Result<Summary, Box<dyn Error>> {
+    let summary = match args.commands {
         Commands::Single {
             cookie,
             header_args,
             url,
             file_path,
             file_name,
+            segments,
+            checksum,
+            checksums_from,
+            transfer,
+            on_conflict,
+            max_size,
+            mirrors,
         } => {
-            let mut urls = Vec::new();
-            let mut file_names = Some(Vec::new());
-
-            urls.push(url);
-            if let Some(file_name) = file_name {
-                file_names.as_mut().unwrap().push(file_name);
-            };
-
-            let mut cookies = Some(Vec::new());
-            if let Some(cookie) = cookie {
-                cookies.as_mut().unwrap().push(cookie);
+            let headers = header_args.unwrap_or_default();
+            let checksum_list = checksums_from
+                .map(|source| ChecksumList::load(&source, cookie.as_deref(), &headers))
+                .transpose()?;
+            let options = DownloadOptions {
+                cookie,
+                headers,
+                segments,
+                checksum_list,
+                on_conflict,
+                max_size,
+                ..DownloadOptions::from(&transfer)
             };
 
-            manager.download(&urls, file_path, file_names, cookies, header_args, false)?;
+            DownloadManager::new()?
+                .download(url, mirrors, file_path, file_name, checksum, &options)?
         }
         Commands::Multi {
             urls,
@@ -42,23 +97,225 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             header_args,
             file_path,
             file_names,
+            segments,
+            jobs,
+            checksums,
+            checksums_from,
+            transfer,
+            total_limit_rate,
+            on_conflict,
+            max_size,
+            input_file,
         } => {
+            let transfer_options = DownloadOptions::from(&transfer);
             // Temp workaround
             let mut file_paths = Some(Vec::<String>::new());
             if let Some(file_path) = file_path.clone() {
                 file_paths.as_mut().unwrap().push(file_path.clone());
             };
 
-            manager.download(&urls, cookie, header_args, file_paths, file_names, true)?;
+            let input = input_file.as_deref().map(InputFile::open).transpose()?;
+            // Standard input is taken by the list, so the rate can't be typed in
+            let total_rate_limiter = if input_file.as_deref() == Some("-") {
+                Arc::new(RateLimiter::new(total_limit_rate.unwrap_or(0)))
+            } else {
+                rate_limiter(total_limit_rate, &transfer_options)
+            };
+
+            let headers = header_args.unwrap_or_default();
+            let checksum_list = checksums_from
+                .map(|source| ChecksumList::load(&source, cookie.as_deref(), &headers))
+                .transpose()?;
+            let options = DownloadOptions {
+                cookie,
+                headers,
+                segments,
+                jobs,
+                checksum_list,
+                total_rate_limiter,
+                on_conflict,
+                max_size,
+                ..transfer_options
+            };
+
+            DownloadManager::new()?
+                .download_multi(&urls, file_paths, file_names, checksums, input, &options)?
+        }
+        Commands::Resume {
+            multi,
+            cookie,
+            header_args,
+            jobs,
+            transfer,
+            total_limit_rate,
+            on_conflict,
+            url,
+        } => {
+            let transfer_options = DownloadOptions::from(&transfer);
+            // Segmented downloads remember their segments in the resume database
+            let options = DownloadOptions {
+                cookie,
+                headers: header_args.unwrap_or_default(),
+                jobs,
+                total_rate_limiter: rate_limiter(total_limit_rate, &transfer_options),
+                on_conflict,
+                ..transfer_options
+            };
+
+            DownloadManager::new()?.resume_download(&url, &options, multi.unwrap_or(false))?
         }
-        Commands::Resume { multi, .. } => {
-            if multi.is_none() || !multi.unwrap() {
-                //download_single_resume(&url)?;
+        Commands::Info {
+            cookie,
+            header_args,
+            json,
+            url,
+        } => {
+            let info = FileInfo::fetch(&url, cookie.as_deref(), &header_args.unwrap_or_default())?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
             } else {
-                //download_download_multi(&url)?;
+                info.print();
             }
+
+            Summary::default()
         }
+        Commands::Sync {
+            cookie,
+            header_args,
+            segments,
+            jobs,
+            transfer,
+            total_limit_rate,
+            prune,
+            dry_run,
+            manifest,
+        } => {
+            let transfer_options = DownloadOptions::from(&transfer);
+            let manifest = Manifest::load(&manifest)?;
+            let entries = manifest.entries()?;
+            // Changed files are replaced, the old one stays until the new one is verified
+            let options = DownloadOptions {
+                cookie,
+                headers: header_args.unwrap_or_default(),
+                segments,
+                jobs,
+                total_rate_limiter: rate_limiter(total_limit_rate, &transfer_options),
+                on_conflict: ConflictPolicy::Overwrite,
+                ..transfer_options
+            };
+
+            DownloadManager::new()?.sync(&manifest, &entries, &options, prune, dry_run)?
+        }
+        Commands::Lock {
+            cookie,
+            header_args,
+            segments,
+            jobs,
+            transfer,
+            manifest,
+        } => {
+            let manifest = Manifest::load(&manifest)?;
+            let entries = manifest.entries()?;
+            let options = DownloadOptions {
+                cookie,
+                headers: header_args.unwrap_or_default(),
+                segments,
+                jobs,
+                on_conflict: ConflictPolicy::Overwrite,
+                ..DownloadOptions::from(&transfer)
+            };
+
+            // Lock what's on disk once it matches the manifest
+            let summary =
+                DownloadManager::new()?.sync(&manifest, &entries, &options, false, false)?;
+            if summary.failed() > 0 {
+                eprintln!("Not every file could be downloaded, so nothing was locked.");
+                return Ok(summary);
+            }
+            let lockfile = Lockfile::resolve(
+                &entries,
+                manifest.dir(),
+                options.cookie.as_deref(),
+                &options.headers,
+            )?;
+            lockfile.save(&manifest.lock_path())?;
+            options.output.status(format!(
+                "Locked {} files in {}.",
+                entries.len(),
+                manifest.lock_path().display()
+            ));
+
+            summary
+        }
+        Commands::Fetch {
+            cookie,
+            header_args,
+            segments,
+            jobs,
+            transfer,
+            total_limit_rate,
+            locked,
+            manifest,
+        } => {
+            let transfer_options = DownloadOptions::from(&transfer);
+            let manifest = Manifest::load(&manifest)?;
+            let mut entries = manifest.entries()?;
+            if locked {
+                Lockfile::load(&manifest.lock_path())?.apply(&mut entries, manifest.dir())?;
+            }
+
+            let options = DownloadOptions {
+                cookie,
+                headers: header_args.unwrap_or_default(),
+                segments,
+                jobs,
+                total_rate_limiter: rate_limiter(total_limit_rate, &transfer_options),
+                on_conflict: ConflictPolicy::Overwrite,
+                ..transfer_options
+            };
+
+            DownloadManager::new()?.sync(&manifest, &entries, &options, false, false)?
+        }
+    };
+
+    Ok(summary)
+}
+
+/// Makes the limiter shared by every download in the run. When run from a terminal, typing
+/// a new rate and pressing Enter changes it while the downloads are going, and the change is
+/// shown the way `options` shows the rest of the run.
+fn rate_limiter(rate: Option<u64>, options: &DownloadOptions) -> Arc<RateLimiter> {
+    let rate_limiter = Arc::new(RateLimiter::new(rate.unwrap_or(0)));
+
+    if io::stdin().is_terminal() {
+        let rate_limiter = Arc::clone(&rate_limiter);
+        let output = options.output;
+        let multi_progress = options.multi_progress.clone();
+        thread::spawn(move || {
+            for line in io::stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match parse_rate(&line) {
+                    Ok(rate) => {
+                        rate_limiter.set_rate(rate);
+                        let message = if rate == 0 {
+                            "Total download rate limit removed.".to_string()
+                        } else {
+                            format!("Total download rate limit set to {}/s.", line.trim())
+                        };
+                        output.notice(&multi_progress, message);
+                    }
+                    Err(e) => output.notice(&multi_progress, e),
+                }
+            }
+        });
     }
 
-    Ok(())
+    rate_limiter
 }
@@ -0,0 +1,468 @@
+use crate::{
+    checksum::{Checksum, digest_file},
+    db::ResumeDb,
+    download::DownloadStatus,
+    lockfile::LOCK_FILE_NAME,
+    naming::{DEFAULT_FILE_NAME, check_file_name, file_name_from_url},
+    transfer::{ResponseInfo, probe},
+};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt, fs,
+    io::Error as IoError,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// A list of files to keep downloaded, read from TOML or YAML:
+///
+/// ```toml
+/// destination = "toolchains"
+///
+/// [[files]]
+/// url = "https://example.com/zig-0.14.0.tar.xz"
+/// mirrors = ["https://mirror.example.org/zig-0.14.0.tar.xz"]
+/// name = "zig.tar.xz"
+/// checksum = "sha256:<hex>"
+/// headers = ["Authorization: Bearer abc"]
+/// ```
+///
+/// Destinations are relative to the manifest's own directory, and default to it. Pruning
+/// needs a `destination` set, so it never removes files from next to the manifest.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    destination: Option<String>,
+    #[serde(default)]
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    url: String,
+    /// Other URLs with the same file. The fastest to answer is downloaded from, switching to
+    /// another when it fails or stalls.
+    #[serde(default)]
+    mirrors: Vec<String>,
+    #[serde(default)]
+    destination: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    checksum: Option<String>,
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+/// A file from the manifest with its name and directory worked out.
+#[derive(Debug, Clone)]
+pub struct SyncEntry {
+    pub url: String,
+    pub mirrors: Vec<String>,
+    pub file_path: String,
+    pub file_name: String,
+    pub checksum: Option<Checksum>,
+    pub headers: Vec<String>,
+}
+
+/// How a file on disk compares to its manifest entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryState {
+    UpToDate,
+    Missing,
+    /// The file on disk doesn't match the checksum in the manifest.
+    Drifted,
+    /// The server has a different version of the file than the one on disk.
+    Changed(String),
+    /// The server couldn't be asked, so the file is left as it is.
+    Unchecked(String),
+}
+
+impl EntryState {
+    /// Whether the file has to be downloaded to match the manifest.
+    pub fn needs_download(&self) -> bool {
+        matches!(self, Self::Missing | Self::Drifted | Self::Changed(_))
+    }
+}
+
+impl fmt::Display for EntryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UpToDate => f.write_str("up to date"),
+            Self::Missing => f.write_str("missing"),
+            Self::Drifted => f.write_str("drifted, it doesn't match its checksum"),
+            Self::Changed(reason) => write!(f, "changed, {reason}"),
+            Self::Unchecked(reason) => write!(f, "not checked, {reason}"),
+        }
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`, as YAML for `.yaml` and `.yml` files and TOML otherwise.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| IoError::new(e.kind(), format!("Could not read {path}: {e}")))?;
+
+        let is_yaml = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension == "yaml" || extension == "yml");
+        let mut manifest: Self = if is_yaml {
+            serde_yaml::from_str(&text)
+                .map_err(|e| format!("{path} is not a valid manifest: {e}"))?
+        } else {
+            toml::from_str(&text).map_err(|e| format!("{path} is not a valid manifest: {e}"))?
+        };
+        manifest.path = PathBuf::from(path);
+
+        Ok(manifest)
+    }
+
+    /// The directory the manifest is in, which destinations are relative to.
+    pub fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Where the lock file for this manifest is kept.
+    pub fn lock_path(&self) -> PathBuf {
+        self.dir().join(LOCK_FILE_NAME)
+    }
+
+    /// Works out where each file goes, refusing manifests with bad names or checksums, or two
+    /// files saved to the same place.
+    pub fn entries(&self) -> Result<Vec<SyncEntry>, String> {
+        let base = self.dir();
+        let mut targets = HashSet::new();
+        let mut entries = Vec::new();
+
+        for file in &self.files {
+            let file_name = match &file.name {
+                Some(name) => check_file_name(name).map_err(|e| format!("{}: {e}", file.url))?,
+                None => {
+                    file_name_from_url(&file.url).unwrap_or_else(|| DEFAULT_FILE_NAME.to_string())
+                }
+            };
+            let checksum = file
+                .checksum
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(|e| format!("{}: {e}", file.url))?;
+            let destination = file.destination.as_ref().or(self.destination.as_ref());
+            let file_path = match destination {
+                Some(destination) => base.join(destination),
+                None => base.to_path_buf(),
+            };
+
+            let target = file_path.join(&file_name);
+            if !targets.insert(target.clone()) {
+                return Err(format!(
+                    "More than one file in the manifest is saved to {}",
+                    target.display()
+                ));
+            }
+
+            entries.push(SyncEntry {
+                url: file.url.clone(),
+                mirrors: file.mirrors.clone(),
+                file_path: file_path.to_string_lossy().to_string(),
+                file_name,
+                checksum,
+                headers: file.headers.clone(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Whether files can be pruned, which needs the manifest to set a destination.
+    pub fn check_prunable(&self) -> Result<(), String> {
+        match &self.destination {
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "{} doesn't set a destination, so --prune could remove files next to it. Set one to prune.",
+                self.path.display()
+            )),
+        }
+    }
+
+    /// Identifies the manifest in the resume database, whatever directory it's run from.
+    pub fn key(&self) -> String {
+        fs::canonicalize(&self.path)
+            .unwrap_or_else(|_| self.path.clone())
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Of the files `sync` downloaded for this manifest, the ones no entry saves to any more,
+    /// which `--prune` removes. Nothing in the manifest's own directory is ever removed.
+    pub fn unlisted_files(&self, entries: &[SyncEntry], synced: &[String]) -> Vec<PathBuf> {
+        let listed = entries
+            .iter()
+            .filter_map(|entry| fs::canonicalize(entry.target_path()).ok())
+            .collect::<HashSet<_>>();
+        let own_dir = fs::canonicalize(self.dir()).ok();
+
+        synced
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.is_file())
+            .filter(|path| fs::canonicalize(path).is_ok_and(|path| !listed.contains(&path)))
+            .filter(|path| path.parent().and_then(|dir| fs::canonicalize(dir).ok()) != own_dir)
+            .collect()
+    }
+}
+
+impl SyncEntry {
+    pub fn target_path(&self) -> PathBuf {
+        Path::new(&self.file_path).join(&self.file_name)
+    }
+
+    /// The main URL and then the mirrors.
+    pub fn urls(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.url).chain(&self.mirrors)
+    }
+
+    /// Asks the server about the file, going through the mirrors when the main URL can't be
+    /// reached. Returns the response and the URL after redirects.
+    pub fn probe(
+        &self,
+        cookie: Option<&str>,
+        headers: &[String],
+    ) -> Result<(ResponseInfo, String), Box<dyn Error>> {
+        let mut headers = headers.to_vec();
+        headers.extend(self.headers.iter().cloned());
+
+        let mut error = None;
+        for url in self.urls() {
+            match probe(url, cookie, &headers) {
+                Ok(probed) => return Ok(probed),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        Err(error.unwrap_or_else(|| "There is no URL to ask".into()))
+    }
+
+    /// Compares the file on disk with the manifest. Files with a checksum are hashed, the
+    /// rest are compared with what the server says about them now and when they were
+    /// downloaded.
+    pub fn state(
+        &self,
+        cookie: Option<&str>,
+        headers: &[String],
+        db: &Mutex<ResumeDb>,
+    ) -> EntryState {
+        let target = self.target_path();
+        let Ok(metadata) = fs::metadata(&target) else {
+            return EntryState::Missing;
+        };
+
+        if let Some(checksum) = &self.checksum {
+            return match digest_file(checksum.algorithm, &target) {
+                Ok(digest) if checksum.matches(&digest) => EntryState::UpToDate,
+                Ok(_) => EntryState::Drifted,
+                Err(e) => EntryState::Unchecked(format!("it could not be hashed: {e}")),
+            };
+        }
+
+        let (response, _) = match self.probe(cookie, headers) {
+            Ok(probed) => probed,
+            Err(e) => {
+                return EntryState::Unchecked(format!("the server could not be reached: {e}"));
+            }
+        };
+
+        if let Some(size) = response.file_size()
+            && size != metadata.len()
+        {
+            return EntryState::Changed(format!(
+                "the server has {size} bytes and the file has {}",
+                metadata.len()
+            ));
+        }
+
+        // The validators saved when it was last downloaded say if it's been replaced since.
+        // Downloads are saved under their main URL, whichever mirror answered.
+        let saved = db
+            .lock()
+            .unwrap()
+            .get_resume(&self.url, &target)
+            .ok()
+            .flatten()
+            .filter(|download| download.status == DownloadStatus::Completed);
+        if let Some(saved) = saved {
+            if saved.etag.is_some() && response.etag.is_some() && saved.etag != response.etag {
+                return EntryState::Changed("the server has a new ETag".to_string());
+            }
+            if saved.last_modified.is_some()
+                && response.last_modified.is_some()
+                && saved.last_modified != response.last_modified
+            {
+                return EntryState::Changed("the server has a newer Last-Modified".to_string());
+            }
+        }
+
+        EntryState::UpToDate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// An empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("download_it-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `text` to a manifest in `dir` and loads it.
+    fn manifest(dir: &Path, text: &str) -> Manifest {
+        let path = dir.join("manifest.toml");
+        fs::write(&path, text).unwrap();
+        Manifest::load(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn works_out_names_and_destinations() {
+        let dir = test_dir("manifest-entries");
+        let manifest = manifest(
+            &dir,
+            r#"
+            destination = "all"
+
+            [[files]]
+            url = "https://example.com/a.iso"
+
+            [[files]]
+            url = "https://example.com/b.iso"
+            name = "renamed.iso"
+            destination = "own"
+
+            [[files]]
+            url = "https://example.com/"
+            "#,
+        );
+
+        let targets = manifest
+            .entries()
+            .unwrap()
+            .iter()
+            .map(SyncEntry::target_path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            [
+                dir.join("all").join("a.iso"),
+                dir.join("own").join("renamed.iso"),
+                dir.join("all").join(DEFAULT_FILE_NAME),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_bad_names_and_two_files_saved_to_one_place() {
+        let dir = test_dir("manifest-refused");
+
+        let bad_name = manifest(
+            &dir,
+            r#"
+            [[files]]
+            url = "https://example.com/a.iso"
+            name = "../a.iso"
+            "#,
+        );
+        assert!(bad_name.entries().is_err());
+
+        let same_target = manifest(
+            &dir,
+            r#"
+            [[files]]
+            url = "https://example.com/a.iso"
+
+            [[files]]
+            url = "https://mirror.example.org/iso/a.iso"
+            "#,
+        );
+        let error = same_target.entries().unwrap_err();
+        assert!(error.contains("More than one file"), "{error}");
+
+        // The same name in different directories is fine
+        let other_dirs = manifest(
+            &dir,
+            r#"
+            [[files]]
+            url = "https://example.com/a.iso"
+            destination = "one"
+
+            [[files]]
+            url = "https://mirror.example.org/iso/a.iso"
+            destination = "two"
+            "#,
+        );
+        assert_eq!(other_dirs.entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn prunes_only_with_a_destination() {
+        let dir = test_dir("manifest-prunable");
+
+        assert!(manifest(&dir, "files = []").check_prunable().is_err());
+        assert!(
+            manifest(&dir, "destination = \"files\"")
+                .check_prunable()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn finds_synced_files_no_longer_listed() {
+        let dir = test_dir("manifest-unlisted");
+        let manifest = manifest(
+            &dir,
+            r#"
+            destination = "files"
+
+            [[files]]
+            url = "https://example.com/kept.iso"
+            "#,
+        );
+        let files = dir.join("files");
+        fs::create_dir_all(&files).unwrap();
+        for path in [
+            files.join("kept.iso"),
+            files.join("dropped.iso"),
+            dir.join("next-to-manifest.iso"),
+        ] {
+            fs::write(path, b"data").unwrap();
+        }
+
+        let synced = [
+            files.join("kept.iso"),
+            files.join("dropped.iso"),
+            // Already gone, so there's nothing to remove
+            files.join("deleted.iso"),
+            // Never removed from the manifest's own directory
+            dir.join("next-to-manifest.iso"),
+        ]
+        .map(|path| path.to_string_lossy().to_string());
+
+        let entries = manifest.entries().unwrap();
+        assert_eq!(
+            manifest.unlisted_files(&entries, &synced),
+            [files.join("dropped.iso")]
+        );
+    }
+}
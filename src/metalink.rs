@@ -0,0 +1,352 @@
+use crate::{
+    checksum::{Algorithm, Checksum, Pieces},
+    naming::sanitize_file_name,
+    transfer::fetch_text,
+};
+use roxmltree::{Document, Node};
+use std::{error::Error, fs, io::Error as IoError, path::PathBuf};
+
+/// A file described by a Metalink document, version 4 (RFC 5854) or 3.
+#[derive(Debug)]
+pub struct MetalinkFile {
+    pub file_name: String,
+    /// The directories the document puts the file in, inside the download directory.
+    pub dir: Option<PathBuf>,
+    pub size: Option<u64>,
+    /// The strongest whole-file hash the document has.
+    pub checksum: Option<Checksum>,
+    pub pieces: Option<Pieces>,
+    /// Where the file can be downloaded from, most preferred first.
+    pub urls: Vec<String>,
+}
+
+/// Whether `source` is a path or URL to a Metalink document, going by its extension.
+pub fn is_metalink(source: &str) -> bool {
+    let path = source
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(source)
+        .to_ascii_lowercase();
+
+    path.ends_with(".meta4") || path.ends_with(".metalink")
+}
+
+/// Reads the files described by the Metalink document at `source`, a path or a URL.
+pub fn load(
+    source: &str,
+    cookie: Option<&str>,
+    headers: &[String],
+) -> Result<Vec<MetalinkFile>, Box<dyn Error>> {
+    let text = if source.contains("://") {
+        fetch_text(source, cookie, headers)?
+    } else {
+        fs::read_to_string(source)
+            .map_err(|e| IoError::new(e.kind(), format!("Could not read {source}: {e}")))?
+    };
+
+    parse(&text).map_err(|e| format!("{source} is not a valid Metalink document: {e}").into())
+}
+
+pub fn parse(text: &str) -> Result<Vec<MetalinkFile>, String> {
+    let document = Document::parse(text).map_err(|e| e.to_string())?;
+    let root = document.root_element();
+    if root.tag_name().name() != "metalink" {
+        return Err(format!(
+            "it starts with <{}> instead of <metalink>",
+            root.tag_name().name()
+        ));
+    }
+
+    // Version 3 keeps the files in <files>, version 4 right under <metalink>
+    let files = root
+        .descendants()
+        .filter(|node| is_element(node, "file"))
+        .map(parse_file)
+        .collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() {
+        return Err("it doesn't describe any files".to_string());
+    }
+
+    Ok(files)
+}
+
+fn parse_file(file: Node) -> Result<MetalinkFile, String> {
+    let name = file
+        .attribute("name")
+        .ok_or("a <file> has no name attribute")?;
+    let (dir, file_name) = split_name(name)?;
+
+    let size = element(file, "size")
+        .and_then(|size| size.text())
+        .map(|size| {
+            size.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("the size of {name} isn't a number"))
+        })
+        .transpose()?;
+
+    // Version 3 keeps the hashes in <verification>, and both keep piece hashes in <pieces>
+    let checksum = file
+        .descendants()
+        .filter(|node| is_element(node, "hash"))
+        .filter(|node| {
+            !node
+                .parent_element()
+                .is_some_and(|p| is_element(&p, "pieces"))
+        })
+        .filter_map(|hash| parse_hash(hash.attribute("type")?, hash.text()?))
+        .max_by_key(|checksum| strength(checksum.algorithm));
+    let pieces = element(file, "pieces").and_then(parse_pieces);
+
+    // Version 4 prefers the lowest priority and version 3 the highest preference. Other
+    // kinds of links, like BitTorrent ones, can't be used.
+    let mut urls = file
+        .descendants()
+        .filter(|node| is_element(node, "url"))
+        .filter(|url| {
+            url.attribute("type")
+                .is_none_or(|kind| matches!(kind, "http" | "https" | "ftp" | "ftps"))
+        })
+        .filter_map(|url| {
+            let rank = match (url.attribute("priority"), url.attribute("preference")) {
+                (Some(priority), _) => priority.parse::<i64>().ok(),
+                (None, Some(preference)) => preference.parse::<i64>().ok().map(|p| 100 - p),
+                (None, None) => None,
+            };
+            Some((rank.unwrap_or(i64::MAX), url.text()?.trim().to_string()))
+        })
+        .filter(|(_, url)| !url.is_empty())
+        .collect::<Vec<_>>();
+    urls.sort_by_key(|(rank, _)| *rank);
+    if urls.is_empty() {
+        return Err(format!(
+            "{name} has no HTTP or FTP URLs to download it from"
+        ));
+    }
+
+    Ok(MetalinkFile {
+        file_name,
+        dir,
+        size,
+        checksum,
+        pieces,
+        urls: urls.into_iter().map(|(_, url)| url).collect(),
+    })
+}
+
+/// Splits a file's name into the directories it goes in and its own name. Names can have
+/// directories in front, but can't reach outside the download directory.
+fn split_name(name: &str) -> Result<(Option<PathBuf>, String), String> {
+    let parts = name
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>();
+    if parts.contains(&"..") {
+        return Err(format!("`{name}` reaches outside the download directory"));
+    }
+
+    let unusable = || format!("`{name}` can't be used as a file name");
+    let (file_name, dirs) = parts.split_last().ok_or_else(unusable)?;
+    let file_name = sanitize_file_name(file_name).ok_or_else(unusable)?;
+    let dir = dirs
+        .iter()
+        .map(|dir| sanitize_file_name(dir))
+        .collect::<Option<PathBuf>>()
+        .ok_or_else(unusable)?;
+
+    Ok(((!dirs.is_empty()).then_some(dir), file_name))
+}
+
+/// Piece hashes in a kind that can't be checked are left out, like any other hash.
+fn parse_pieces(pieces: Node) -> Option<Pieces> {
+    let algorithm = pieces.attribute("type")?.parse::<Algorithm>().ok()?;
+    let length = pieces.attribute("length")?.parse::<u64>().ok()?;
+    if length == 0 {
+        return None;
+    }
+
+    // Version 3 numbers the pieces, version 4 just lists them in order
+    let mut hashes = pieces
+        .children()
+        .filter(|node| is_element(node, "hash"))
+        .enumerate()
+        .map(|(index, hash)| {
+            let index = hash
+                .attribute("piece")
+                .and_then(|piece| piece.parse::<usize>().ok())
+                .unwrap_or(index);
+            let digest = parse_hash(algorithm.name(), hash.text()?)?.digest;
+            Some((index, digest))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    hashes.sort_by_key(|(index, _)| *index);
+
+    Some(Pieces {
+        algorithm,
+        length,
+        digests: hashes.into_iter().map(|(_, digest)| digest).collect(),
+    })
+}
+
+fn parse_hash(kind: &str, digest: &str) -> Option<Checksum> {
+    format!("{kind}:{}", digest.trim()).parse().ok()
+}
+
+/// Ranks the algorithms so the strongest hash a file has is the one checked.
+fn strength(algorithm: Algorithm) -> u8 {
+    match algorithm {
+        Algorithm::Sha512 => 4,
+        Algorithm::Sha256 => 3,
+        Algorithm::Blake3 => 2,
+        Algorithm::Sha1 => 1,
+        Algorithm::Md5 => 0,
+    }
+}
+
+fn is_element(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+/// The first element called `name` inside `node`.
+fn element<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.descendants().find(|child| is_element(child, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+    #[test]
+    fn reads_a_version_4_document() {
+        let files = parse(&format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <file name="isos/debian.iso">
+                <size>2097152</size>
+                <hash type="sha-1">{SHA1}</hash>
+                <hash type="sha-256">{SHA256}</hash>
+                <pieces length="1048576" type="sha-1">
+                  <hash>{SHA1}</hash>
+                  <hash>{SHA1}</hash>
+                </pieces>
+                <url priority="2">https://b.example/debian.iso</url>
+                <url priority="1">https://a.example/debian.iso</url>
+                <url>https://c.example/debian.iso</url>
+                <metaurl mediatype="torrent">https://a.example/debian.torrent</metaurl>
+              </file>
+            </metalink>"#
+        ))
+        .unwrap();
+
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.file_name, "debian.iso");
+        assert_eq!(file.dir, Some(PathBuf::from("isos")));
+        assert_eq!(file.size, Some(2097152));
+        assert_eq!(
+            file.checksum,
+            Some(format!("sha256:{SHA256}").parse().unwrap())
+        );
+        let pieces = file.pieces.as_ref().unwrap();
+        assert_eq!(pieces.algorithm, Algorithm::Sha1);
+        assert_eq!(pieces.length, 1048576);
+        assert_eq!(pieces.digests, [SHA1, SHA1]);
+        assert_eq!(
+            file.urls,
+            [
+                "https://a.example/debian.iso",
+                "https://b.example/debian.iso",
+                "https://c.example/debian.iso",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_a_version_3_document() {
+        let files = parse(&format!(
+            r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+              <files>
+                <file name="a.bin">
+                  <verification>
+                    <hash type="md5">d41d8cd98f00b204e9800998ecf8427e</hash>
+                    <pieces length="4" type="sha1">
+                      <hash piece="1">{SHA1}</hash>
+                      <hash piece="0">{}</hash>
+                    </pieces>
+                  </verification>
+                  <resources>
+                    <url type="bittorrent" preference="100">https://a.example/a.torrent</url>
+                    <url type="http" preference="10">http://slow.example/a.bin</url>
+                    <url type="ftp" preference="90">ftp://fast.example/a.bin</url>
+                  </resources>
+                </file>
+                <file name="b.bin">
+                  <resources><url type="https">https://a.example/b.bin</url></resources>
+                </file>
+              </files>
+            </metalink>"#,
+            SHA1.replace('d', "e")
+        ))
+        .unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].dir, None);
+        assert_eq!(
+            files[0].checksum.as_ref().unwrap().algorithm,
+            Algorithm::Md5
+        );
+        assert_eq!(
+            files[0].pieces.as_ref().unwrap().digests,
+            [SHA1.replace('d', "e"), SHA1.to_string()]
+        );
+        assert_eq!(
+            files[0].urls,
+            ["ftp://fast.example/a.bin", "http://slow.example/a.bin"]
+        );
+        assert_eq!(files[1].file_name, "b.bin");
+        assert_eq!(files[1].checksum, None);
+    }
+
+    #[test]
+    fn refuses_malformed_documents() {
+        for text in [
+            "",
+            "not xml",
+            "<metalink>",
+            "<feed><file name=\"a\"><url>https://a.example/a</url></file></feed>",
+            "<metalink></metalink>",
+            "<metalink><file><url>https://a.example/a</url></file></metalink>",
+            "<metalink><file name=\"a\"></file></metalink>",
+            "<metalink><file name=\"a\"><url type=\"bittorrent\">https://a.example/a</url></file></metalink>",
+            "<metalink><file name=\"a\"><size>big</size><url>https://a.example/a</url></file></metalink>",
+        ] {
+            assert!(parse(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn keeps_file_names_inside_the_download_directory() {
+        for name in ["../evil.sh", "a/../../evil.sh", "", "/", "a/.."] {
+            assert!(split_name(name).is_err(), "{name:?}");
+        }
+        assert_eq!(
+            split_name("/etc/./passwd"),
+            Ok((Some(PathBuf::from("etc")), "passwd".to_string()))
+        );
+        assert_eq!(split_name("a.iso"), Ok((None, "a.iso".to_string())));
+    }
+
+    #[test]
+    fn recognises_metalink_sources() {
+        assert!(is_metalink("https://a.example/debian.meta4"));
+        assert!(is_metalink("files/debian.METALINK"));
+        assert!(is_metalink("https://a.example/debian.meta4?token=1#top"));
+        assert!(!is_metalink("https://a.example/debian.iso"));
+        assert!(!is_metalink(
+            "https://a.example/download?file=debian.meta4.iso"
+        ));
+    }
+}
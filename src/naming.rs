@@ -0,0 +1,493 @@
+use crate::transfer::ResponseInfo;
+use percent_encoding::percent_decode_str;
+use std::path::Path;
+use url::Url;
+
+/// What to call a file when neither the response nor the URL has a name for it.
+pub const DEFAULT_FILE_NAME: &str = "download";
+
+/// The longest file name to save, in bytes. Most file systems allow 255, this leaves room
+/// for what gets added next to a download, like `.corrupt`.
+const MAX_FILE_NAME_LEN: usize = 200;
+
+/// Extensions longer than this aren't kept when a long name is cut down.
+const MAX_EXTENSION_LEN: usize = 16;
+
+/// Characters Windows won't allow in a file name.
+const RESERVED_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows won't allow as a file name, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Picks the name to save a response as: the Content-Disposition file name, then the last
+/// part of the URL it ended up at after redirects. An extension for the content type is
+/// added when the name doesn't have one.
+pub fn file_name_from_response(response: &ResponseInfo, effective_url: &str) -> Option<String> {
+    let file_name = response
+        .content_disposition
+        .as_deref()
+        .and_then(content_disposition_file_name)
+        .or_else(|| file_name_from_url(effective_url))?;
+
+    if Path::new(&file_name).extension().is_some() {
+        return Some(file_name);
+    }
+
+    match response
+        .content_type
+        .as_deref()
+        .and_then(extension_for_mime)
+    {
+        Some(extension) => sanitize_file_name(&format!("{file_name}.{extension}")),
+        None => Some(file_name),
+    }
+}
+
+/// The percent-decoded last part of a URL's path, without the query string.
+pub fn file_name_from_url(url: &str) -> Option<String> {
+    let path = match Url::parse(url) {
+        Ok(url) => url.path().to_string(),
+        // Not a full URL, so cut the query and fragment off by hand
+        Err(_) => url.split(['?', '#']).next().unwrap_or_default().to_string(),
+    };
+
+    let last = path.rsplit('/').next()?;
+    let file_name = percent_decode_str(last).decode_utf8_lossy();
+
+    sanitize_file_name(&file_name)
+}
+
+/// Reads the file name out of a `Content-Disposition` header, preferring the RFC 5987
+/// `filename*` parameter over the plain `filename` one.
+pub fn content_disposition_file_name(value: &str) -> Option<String> {
+    let mut file_name = None;
+    let mut extended_file_name = None;
+
+    for param in split_params(value) {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename*" => extended_file_name = decode_ext_value(value.trim()),
+            "filename" => file_name = Some(unquote(value.trim())),
+            _ => {}
+        }
+    }
+
+    extended_file_name
+        .or(file_name)
+        .and_then(|file_name| sanitize_file_name(&file_name))
+}
+
+/// Turns a name from a URL, a response or the command line into one that is safe to create
+/// inside the download directory. Directories in front of it are dropped, control and
+/// reserved characters are taken out, Windows device names are prefixed and long names are
+/// cut down, keeping the extension. Returns `None` if nothing usable is left.
+pub fn sanitize_file_name(file_name: &str) -> Option<String> {
+    let file_name = file_name.rsplit(['/', '\\']).next()?;
+    let file_name = file_name
+        .chars()
+        .filter(|&c| !c.is_control() && !is_bidi_control(c))
+        .map(|c| if RESERVED_CHARS.contains(&c) { '_' } else { c })
+        .collect::<String>();
+
+    // Windows drops trailing dots and spaces, which would turn `..` back into a directory
+    let file_name = file_name.trim().trim_end_matches(['.', ' ']);
+    if file_name.is_empty() {
+        return None;
+    }
+
+    let file_name = truncate_file_name(file_name);
+    let stem = file_name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        return Some(format!("_{file_name}"));
+    }
+
+    Some(file_name)
+}
+
+/// Checks a file name given on the command line. Anything that would reach outside the
+/// download directory is refused instead of quietly changed.
+pub fn check_file_name(file_name: &str) -> Result<String, String> {
+    if file_name.contains(['/', '\\', '\0']) || matches!(file_name.trim(), "." | "..") {
+        return Err(format!(
+            "`{}` is not a file name, use --file-path to choose the directory",
+            file_name.escape_debug()
+        ));
+    }
+
+    sanitize_file_name(file_name)
+        .ok_or_else(|| format!("`{}` is not a usable file name", file_name.escape_debug()))
+}
+
+/// The name a file is downloaded under until it's complete.
+pub fn part_file_name(file_name: &str) -> String {
+    format!("{file_name}.part")
+}
+
+/// `file_name` with `number` worked in before its extension, like `file (1).iso`.
+pub fn numbered_file_name(file_name: &str, number: usize) -> String {
+    // A leading dot is part of the name, like in `.bashrc`
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.trim_start_matches('.').is_empty() => {
+            match stem.strip_suffix(".tar") {
+                Some(stem) => (stem, format!(".tar.{extension}")),
+                None => (stem, format!(".{extension}")),
+            }
+        }
+        _ => (file_name, String::new()),
+    };
+
+    format!("{stem} ({number}){extension}")
+}
+
+/// Unicode controls that reorder text, which can make `evil\u{202e}fdp.exe` show up as
+/// `evilexe.pdf`.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Cuts a name down to `MAX_FILE_NAME_LEN` bytes, keeping its extension when it has a
+/// short one.
+fn truncate_file_name(file_name: &str) -> String {
+    if file_name.len() <= MAX_FILE_NAME_LEN {
+        return file_name.to_string();
+    }
+
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= MAX_EXTENSION_LEN => {
+            (stem, format!(".{extension}"))
+        }
+        _ => (file_name, String::new()),
+    };
+
+    let mut end = MAX_FILE_NAME_LEN - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{extension}", stem[..end].trim_end_matches(['.', ' ']))
+}
+
+/// Splits a header value on the `;` between its parameters, leaving quoted strings whole.
+fn split_params(value: &str) -> Vec<String> {
+    let mut params = vec![String::new()];
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+
+        if let Some(param) = params.last_mut() {
+            param.push(c);
+        }
+    }
+
+    params
+}
+
+/// Takes the quotes and backslash escapes off a quoted string, or returns a token as is.
+fn unquote(value: &str) -> String {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .map(|inner| inner.strip_suffix('"').unwrap_or(inner))
+    else {
+        return value.to_string();
+    };
+
+    let mut unquoted = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
+
+/// Decodes an RFC 5987 value like `UTF-8'en'na%C3%AFve.txt`.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+
+    let bytes = percent_decode_str(encoded).collect::<Vec<_>>();
+    if charset.eq_ignore_ascii_case("utf-8") {
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        // Every ISO-8859-1 byte is the Unicode code point with the same number
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// The usual extension for a `Content-Type`, if it says anything more specific than
+/// "some bytes".
+fn extension_for_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    // mime_guess lists extensions alphabetically, so pick the common ones by hand
+    let extension = match mime.as_str() {
+        "" | "application/octet-stream" | "binary/octet-stream" => return None,
+        "text/html" => "html",
+        "text/plain" => "txt",
+        "text/xml" | "application/xml" => "xml",
+        "application/json" => "json",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-xz" => "xz",
+        "application/zstd" => "zst",
+        "application/x-iso9660-image" => "iso",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "video/mp4" => "mp4",
+        "audio/mpeg" => "mp3",
+        mime => mime_guess::get_mime_extensions_str(mime)?.first()?,
+    };
+
+    Some(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_directories_in_front_of_the_name() {
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(sanitize_file_name("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(
+            sanitize_file_name("..\\..\\Windows\\win.ini").as_deref(),
+            Some("win.ini")
+        );
+        assert_eq!(
+            sanitize_file_name("C:\\evil.exe").as_deref(),
+            Some("evil.exe")
+        );
+    }
+
+    #[test]
+    fn refuses_names_that_are_only_dots_or_blank() {
+        for file_name in [
+            "", " ", ".", "..", "...", "../", "foo/..", "..\\", " .. ", "\0",
+        ] {
+            assert_eq!(sanitize_file_name(file_name), None, "{file_name:?}");
+        }
+    }
+
+    #[test]
+    fn removes_control_and_reserved_characters() {
+        assert_eq!(sanitize_file_name("a\0b.txt").as_deref(), Some("ab.txt"));
+        assert_eq!(
+            sanitize_file_name("line\r\nbreak.txt").as_deref(),
+            Some("linebreak.txt")
+        );
+        assert_eq!(
+            sanitize_file_name("what?<is>:this|*\".txt").as_deref(),
+            Some("what__is__this___.txt")
+        );
+        assert_eq!(
+            sanitize_file_name("invoice\u{202e}fdp.exe").as_deref(),
+            Some("invoicefdp.exe")
+        );
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces() {
+        assert_eq!(
+            sanitize_file_name("  report.pdf . ").as_deref(),
+            Some("report.pdf")
+        );
+        assert_eq!(sanitize_file_name(".hidden").as_deref(), Some(".hidden"));
+    }
+
+    #[test]
+    fn prefixes_windows_device_names() {
+        assert_eq!(sanitize_file_name("CON").as_deref(), Some("_CON"));
+        assert_eq!(sanitize_file_name("nul.txt").as_deref(), Some("_nul.txt"));
+        assert_eq!(
+            sanitize_file_name("lpt1.tar.gz").as_deref(),
+            Some("_lpt1.tar.gz")
+        );
+        assert_eq!(
+            sanitize_file_name("console.log").as_deref(),
+            Some("console.log")
+        );
+    }
+
+    #[test]
+    fn caps_long_names_and_keeps_the_extension() {
+        let file_name = sanitize_file_name(&format!("{}.iso", "a".repeat(1000))).unwrap();
+        assert_eq!(file_name.len(), MAX_FILE_NAME_LEN);
+        assert!(file_name.ends_with("a.iso"));
+
+        // Multi-byte characters aren't cut in half
+        let file_name = sanitize_file_name(&format!("{}.txt", "é".repeat(300))).unwrap();
+        assert!(file_name.len() <= MAX_FILE_NAME_LEN);
+        assert!(file_name.ends_with("é.txt"));
+
+        let file_name = sanitize_file_name(&"b".repeat(300)).unwrap();
+        assert_eq!(file_name.len(), MAX_FILE_NAME_LEN);
+    }
+
+    #[test]
+    fn numbers_go_before_the_extension() {
+        assert_eq!(numbered_file_name("file.iso", 1), "file (1).iso");
+        assert_eq!(numbered_file_name("file.tar.gz", 2), "file (2).tar.gz");
+        assert_eq!(numbered_file_name("v1.2.zip", 1), "v1.2 (1).zip");
+        assert_eq!(numbered_file_name("README", 3), "README (3)");
+        assert_eq!(numbered_file_name(".bashrc", 1), ".bashrc (1)");
+    }
+
+    #[test]
+    fn url_names_cannot_escape() {
+        assert_eq!(
+            file_name_from_url("https://example.com/files/..%2F..%2Fetc%2Fpasswd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            file_name_from_url("https://example.com/files/%2e%2e").as_deref(),
+            None
+        );
+        assert_eq!(
+            file_name_from_url("https://example.com/a%00b.iso?x=1").as_deref(),
+            Some("ab.iso")
+        );
+        assert_eq!(file_name_from_url("https://example.com/").as_deref(), None);
+    }
+
+    #[test]
+    fn content_disposition_names_cannot_escape() {
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"../../.bashrc\"").as_deref(),
+            Some(".bashrc")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; filename*=UTF-8''..%2F..%2Fevil.sh")
+                .as_deref(),
+            Some("evil.sh")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"C:\\\\Windows\\\\evil.dll\"")
+                .as_deref(),
+            Some("evil.dll")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"..\"").as_deref(),
+            None
+        );
+    }
+
+    #[test]
+    fn response_names_prefer_the_extended_content_disposition_name() {
+        let response = ResponseInfo {
+            content_disposition: Some(
+                "attachment; filename=\"plain.iso\"; filename*=UTF-8''r%C3%A9sum%C3%A9.iso"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            file_name_from_response(&response, "https://example.com/download?id=1").as_deref(),
+            Some("résumé.iso")
+        );
+
+        // Whichever order they come in
+        let response = ResponseInfo {
+            content_disposition: Some(
+                "attachment; filename*=UTF-8''extended.iso; filename=\"plain.iso\"".to_string(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            file_name_from_response(&response, "https://example.com/download").as_deref(),
+            Some("extended.iso")
+        );
+    }
+
+    #[test]
+    fn response_names_come_from_the_url_after_redirects() {
+        // The URL asked for was https://example.com/latest, which redirected here
+        let response = ResponseInfo {
+            content_type: Some("application/octet-stream".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            file_name_from_response(
+                &response,
+                "https://cdn.example.com/releases/tool-1.2.3.tar.gz?token=abc"
+            )
+            .as_deref(),
+            Some("tool-1.2.3.tar.gz")
+        );
+
+        // A Content-Disposition name still wins over it
+        let response = ResponseInfo {
+            content_disposition: Some("attachment; filename=\"tool.tar.gz\"".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            file_name_from_response(&response, "https://cdn.example.com/blob/8f3a").as_deref(),
+            Some("tool.tar.gz")
+        );
+    }
+
+    #[test]
+    fn response_names_with_an_added_extension_stay_capped() {
+        let response = ResponseInfo {
+            content_type: Some("application/pdf".to_string()),
+            ..Default::default()
+        };
+        let url = format!("https://example.com/{}", "c".repeat(400));
+        let file_name = file_name_from_response(&response, &url).unwrap();
+        assert!(file_name.len() <= MAX_FILE_NAME_LEN);
+        assert!(file_name.ends_with(".pdf"));
+    }
+
+    #[test]
+    fn command_line_names_that_escape_are_refused() {
+        for file_name in [
+            "../x",
+            "/etc/passwd",
+            "dir/file",
+            "..\\x",
+            "a\0b",
+            "..",
+            ".",
+        ] {
+            assert!(check_file_name(file_name).is_err(), "{file_name:?}");
+        }
+        assert_eq!(check_file_name("ok.iso").as_deref(), Ok("ok.iso"));
+        assert_eq!(check_file_name("a:b.txt").as_deref(), Ok("a_b.txt"));
+    }
+}
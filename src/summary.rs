@@ -0,0 +1,201 @@
+use crate::download::{Download, DownloadStatus};
+use indicatif::HumanBytes;
+use std::process::ExitCode;
+
+/// How a run went as a whole, which decides the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every file downloaded, or was already there.
+    Success,
+    /// Nothing downloaded, or the run stopped with an error.
+    Failure,
+    /// The command line couldn't be used.
+    UsageError,
+    /// Some files downloaded and some didn't.
+    PartialFailure,
+    /// A file downloaded but didn't match its checksum, size or piece digests.
+    VerificationFailure,
+}
+
+impl Outcome {
+    /// 2 for usage errors is the same code the argument parser exits with.
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::Success => 0,
+            Self::Failure => 1,
+            Self::UsageError => 2,
+            Self::PartialFailure => 3,
+            Self::VerificationFailure => 4,
+        })
+    }
+}
+
+/// The downloads a run finished, reported in a table at the end.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub downloads: Vec<Download>,
+}
+
+impl Summary {
+    pub fn new(downloads: Vec<Download>) -> Self {
+        Self { downloads }
+    }
+
+    pub fn failed(&self) -> usize {
+        self.downloads
+            .iter()
+            .filter(|download| !succeeded(download))
+            .count()
+    }
+
+    /// A file that failed verification outweighs any other failure, as it means something
+    /// other than the expected file was served.
+    pub fn outcome(&self) -> Outcome {
+        let failed = self.failed();
+
+        if self
+            .downloads
+            .iter()
+            .any(|download| download.status == DownloadStatus::VerificationFailed)
+        {
+            Outcome::VerificationFailure
+        } else if failed == 0 {
+            Outcome::Success
+        } else if failed == self.downloads.len() {
+            Outcome::Failure
+        } else {
+            Outcome::PartialFailure
+        }
+    }
+
+    /// Prints each file's status, size, how long it took, its average speed and what went
+    /// wrong, one line each.
+    pub fn print(&self) {
+        if self.downloads.is_empty() {
+            return;
+        }
+
+        let mut rows = vec![[
+            "STATUS".to_string(),
+            "SIZE".to_string(),
+            "TIME".to_string(),
+            "SPEED".to_string(),
+            "URL".to_string(),
+            "ERROR".to_string(),
+        ]];
+        for download in &self.downloads {
+            let (size, speed) = match download.status {
+                DownloadStatus::Skipped => ("-".to_string(), "-".to_string()),
+                _ => (
+                    HumanBytes(download.downloaded).to_string(),
+                    average_speed(download)
+                        .map_or("-".to_string(), |speed| format!("{}/s", HumanBytes(speed))),
+                ),
+            };
+            let time = download.duration.map_or("-".to_string(), |duration| {
+                format!("{:.1}s", duration.as_secs_f64())
+            });
+
+            rows.push([
+                download.status.to_string(),
+                size,
+                time,
+                speed,
+                download.url.clone(),
+                download.error.clone().unwrap_or_default(),
+            ]);
+        }
+
+        let mut widths = [0; 6];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        eprintln!();
+        for row in rows {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<_>>()
+                .join("  ");
+            eprintln!("{}", line.trim_end());
+        }
+    }
+}
+
+fn succeeded(download: &Download) -> bool {
+    matches!(
+        download.status,
+        DownloadStatus::Completed | DownloadStatus::Skipped
+    )
+}
+
+/// Bytes per second over what this run downloaded, leaving out what an earlier run did.
+fn average_speed(download: &Download) -> Option<u64> {
+    let (_, resumed_from) = download.started?;
+    let seconds = download.duration?.as_secs_f64();
+    let downloaded = download.downloaded.saturating_sub(resumed_from);
+
+    (seconds > 0.0).then(|| (downloaded as f64 / seconds) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(statuses: &[DownloadStatus]) -> Summary {
+        Summary::new(
+            statuses
+                .iter()
+                .enumerate()
+                .map(|(index, status)| {
+                    let mut download = Download::new(
+                        format!("https://example.com/{index}"),
+                        None,
+                        Some(".".to_string()),
+                        None,
+                    );
+                    download.status = status.clone();
+                    download
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn maps_statuses_to_outcomes() {
+        use DownloadStatus::*;
+
+        for (statuses, outcome) in [
+            (&[Completed, Skipped][..], Outcome::Success),
+            (&[Failed, Failed], Outcome::Failure),
+            (&[Completed, Failed], Outcome::PartialFailure),
+            (&[Skipped, Failed], Outcome::PartialFailure),
+            (&[VerificationFailed], Outcome::VerificationFailure),
+            // A file that didn't verify outweighs the others failing or not
+            (
+                &[Completed, VerificationFailed],
+                Outcome::VerificationFailure,
+            ),
+            (&[Failed, VerificationFailed], Outcome::VerificationFailure),
+        ] {
+            assert_eq!(summary(statuses).outcome(), outcome, "{statuses:?}");
+        }
+    }
+
+    #[test]
+    fn exits_with_a_code_for_each_outcome() {
+        for (outcome, code) in [
+            (Outcome::Success, 0),
+            (Outcome::Failure, 1),
+            (Outcome::UsageError, 2),
+            (Outcome::PartialFailure, 3),
+            (Outcome::VerificationFailure, 4),
+        ] {
+            assert_eq!(outcome.exit_code(), ExitCode::from(code));
+        }
+    }
+}
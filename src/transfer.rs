@@ -0,0 +1,1085 @@
+use crate::{
+    checksum::{Algorithm, Checksum, Hasher, parse_digest_header},
+    conflict::{Claims, ConflictPolicy, Resolution},
+    db::ResumeDb,
+    naming::{DEFAULT_FILE_NAME, file_name_from_response, file_name_from_url, part_file_name},
+};
+use curl::easy::{Easy2, Handler, List, WriteError};
+use indicatif::{HumanBytes, ProgressBar};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
+
+/// How often a running download writes its byte offset to the resume database.
+const PROGRESS_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The smallest byte range worth opening another connection for.
+pub const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// The parts of an HTTP response's headers a transfer cares about.
+#[derive(Debug, Default, Clone)]
+pub struct ResponseInfo {
+    pub status: u32,
+    pub content_length: Option<u64>,
+    pub content_range: Option<ContentRange>,
+    pub accept_ranges: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// How long the server asked us to wait before trying again.
+    pub retry_after: Option<Duration>,
+    pub content_type: Option<String>,
+    pub content_disposition: Option<String>,
+    /// Where a redirect points.
+    pub location: Option<String>,
+    /// Digests of the file the server sent along with it.
+    pub digests: Vec<Checksum>,
+}
+
+impl ResponseInfo {
+    /// The size of the whole remote file, from `Content-Range` for a partial response.
+    pub fn file_size(&self) -> Option<u64> {
+        match self.status {
+            206 => self.content_range.and_then(|range| range.total),
+            _ => self.content_length,
+        }
+    }
+
+    /// Whether the server can send parts of the file.
+    pub fn supports_ranges(&self) -> bool {
+        self.accept_ranges || self.status == 206
+    }
+
+    /// Feeds one raw header line from cURL into the response.
+    pub fn parse_header_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+
+        // A new status line means a redirect or interim response, so start from scratch
+        if line.starts_with("HTTP/") {
+            *self = Self {
+                status: line
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|code| code.parse().ok())
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            return;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return;
+        };
+        let value = value.trim();
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => self.content_length = value.parse().ok(),
+            "content-range" => self.content_range = ContentRange::parse(value),
+            "accept-ranges" => self.accept_ranges = value.eq_ignore_ascii_case("bytes"),
+            "etag" => self.etag = Some(value.to_string()),
+            "last-modified" => self.last_modified = Some(value.to_string()),
+            "retry-after" => self.retry_after = parse_retry_after(value),
+            "content-type" => self.content_type = Some(value.to_string()),
+            "content-disposition" => self.content_disposition = Some(value.to_string()),
+            "location" => self.location = Some(value.to_string()),
+            name => self.digests.extend(parse_digest_header(name, value)),
+        }
+    }
+
+    /// Feeds one raw header line into the response like `parse_header_line`, moving
+    /// `effective_url` along when the response before it was a redirect.
+    pub fn track_header_line(&mut self, line: &[u8], effective_url: &mut String) {
+        if line.starts_with(b"HTTP/")
+            && (300..400).contains(&self.status)
+            && let Some(location) = &self.location
+            && let Ok(url) = Url::parse(effective_url).and_then(|url| url.join(location))
+        {
+            *effective_url = url.to_string();
+        }
+
+        self.parse_header_line(line);
+    }
+}
+
+/// Reads a `Retry-After` header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Why a transfer failed, and whether trying it again could help.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub message: String,
+    /// Timeouts, dropped connections and overloaded servers are worth retrying, a missing
+    /// file or a refused request isn't.
+    pub transient: bool,
+    pub retry_after: Option<Duration>,
+    /// Whether it came from the server or the connection to it, so a mirror could do better.
+    pub from_server: bool,
+}
+
+impl Failure {
+    pub fn permanent(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            transient: false,
+            retry_after: None,
+            from_server: false,
+        }
+    }
+}
+
+/// A parsed `Content-Range: bytes <start>-<end>/<total>` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentRange {
+    /// The first byte of the body, `None` for an unsatisfied range (`bytes */<total>`).
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub total: Option<u64>,
+}
+
+impl ContentRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let range = value.strip_prefix("bytes")?.trim();
+        let (span, total) = range.split_once('/')?;
+
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+
+        let (start, end) = match span.trim() {
+            "*" => (None, None),
+            span => {
+                let (start, end) = span.split_once('-')?;
+                (
+                    Some(start.trim().parse().ok()?),
+                    Some(end.trim().parse().ok()?),
+                )
+            }
+        };
+
+        Some(Self { start, end, total })
+    }
+}
+
+/// One byte range of a file that is downloaded over several connections at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub index: usize,
+    pub start: u64,
+    /// The last byte of the range, inclusive like the `Range` header.
+    pub end: u64,
+    pub downloaded: u64,
+}
+
+impl Segment {
+    /// Splits `size` bytes into at most `count` segments of at least `MIN_SEGMENT_SIZE` bytes.
+    pub fn split(size: u64, count: usize) -> Vec<Self> {
+        let count = (count as u64).min(size / MIN_SEGMENT_SIZE).max(1);
+        let segment_size = size.div_ceil(count);
+
+        (0..count)
+            .map(|index| {
+                let start = index * segment_size;
+                Self {
+                    index: index as usize,
+                    start,
+                    end: (start + segment_size).min(size) - 1,
+                    downloaded: 0,
+                }
+            })
+            .collect()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.downloaded >= self.size()
+    }
+}
+
+/// A token bucket that caps how fast all the transfers sharing it download together.
+/// The rate can be changed while they run.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second, 0 for no limit.
+    rate: AtomicU64,
+    /// The bytes that can be taken right now, and when that was worked out.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Takes `bytes` from the bucket if there are any left. Going over by part of a chunk is
+    /// allowed, the next chunks just wait for the bucket to fill back up.
+    pub fn take(&self, bytes: usize) -> bool {
+        let rate = self.rate();
+        if rate == 0 {
+            return true;
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        Self::refill(&mut bucket, rate);
+        if bucket.0 <= 0.0 {
+            return false;
+        }
+        bucket.0 -= bytes as f64;
+
+        true
+    }
+
+    /// Whether a paused transfer can start taking bytes again.
+    pub fn ready(&self) -> bool {
+        let rate = self.rate();
+        if rate == 0 {
+            return true;
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        Self::refill(&mut bucket, rate);
+
+        bucket.0 > 0.0
+    }
+
+    fn refill(bucket: &mut (f64, Instant), rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.1).as_secs_f64();
+        // Hold at most a second's worth so a quiet spell can't be followed by a burst
+        bucket.0 = (bucket.0 + elapsed * rate as f64).min(rate as f64);
+        bucket.1 = now;
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+/// Builds a cURL handle with the options every request for a download shares.
+pub fn new_easy<H: Handler>(
+    handler: H,
+    url: &str,
+    cookie: Option<&str>,
+    headers: &[String],
+) -> Result<Easy2<H>, curl::Error> {
+    // Create a cURL easy struct
+    let mut easy = Easy2::new(handler);
+    // Allow following redirects for the download
+    easy.follow_location(true)?;
+    // Treat HTTP error responses as failed downloads instead of saving the error page
+    easy.fail_on_error(true)?;
+    // Give it the application as the User Agent
+    easy.useragent("download_it/0.1.0")?;
+    // Pass it the download URL
+    easy.url(url)?;
+    // Get download progress from it
+    easy.progress(true)?;
+
+    // If a cookie file was passed, give it to the cURL struct
+    if let Some(cookie) = cookie {
+        easy.cookie(cookie)?;
+    }
+
+    // If header arguments were given, pass them to the cURL struct
+    let mut list = List::new();
+    for header in headers {
+        list.append(header)?;
+    }
+    easy.http_headers(list)?;
+
+    Ok(easy)
+}
+
+/// Collects a response body in memory, for small files like checksum lists.
+#[derive(Debug, Default)]
+struct BodyCollector(Vec<u8>);
+
+impl Handler for BodyCollector {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        self.0.extend_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+/// Downloads a small text file straight into memory.
+pub fn fetch_text(
+    url: &str,
+    cookie: Option<&str>,
+    headers: &[String],
+) -> Result<String, Box<dyn Error>> {
+    let mut easy = new_easy(BodyCollector::default(), url, cookie, headers)?;
+    easy.progress(false)?;
+    easy.perform()?;
+
+    Ok(String::from_utf8_lossy(&easy.get_ref().0).into_owned())
+}
+
+/// Collects the headers of a response and stops at its body.
+#[derive(Debug)]
+struct HeaderCollector {
+    effective_url: String,
+    response: ResponseInfo,
+}
+
+impl Handler for HeaderCollector {
+    fn header(&mut self, data: &[u8]) -> bool {
+        self.response
+            .track_header_line(data, &mut self.effective_url);
+        true
+    }
+
+    fn write(&mut self, _data: &[u8]) -> Result<usize, WriteError> {
+        Ok(0)
+    }
+}
+
+/// Asks the server about a file without downloading it, the same way a download does before
+/// it starts: with a `HEAD` request, or a request for its first byte if the server won't
+/// answer that. Returns the response and the URL it came from after redirects.
+pub fn probe(
+    url: &str,
+    cookie: Option<&str>,
+    headers: &[String],
+) -> Result<(ResponseInfo, String), Box<dyn Error>> {
+    probe_with(url, cookie, headers, false).or_else(|_| probe_with(url, cookie, headers, true))
+}
+
+fn probe_with(
+    url: &str,
+    cookie: Option<&str>,
+    headers: &[String],
+    ranged: bool,
+) -> Result<(ResponseInfo, String), Box<dyn Error>> {
+    let handler = HeaderCollector {
+        effective_url: url.to_string(),
+        response: ResponseInfo::default(),
+    };
+    let mut easy = new_easy(handler, url, cookie, headers)?;
+    easy.progress(false)?;
+    if ranged {
+        easy.range("0-0")?;
+    } else {
+        easy.nobody(true)?;
+    }
+
+    let result = easy.perform();
+    let handler = easy.get_ref();
+    match result {
+        // The ranged request is cut off once its headers are in
+        Err(_) if ranged && (200..300).contains(&handler.response.status) => {}
+        Err(e) => return Err(e.into()),
+        Ok(_) => {}
+    }
+
+    Ok((handler.response.clone(), handler.effective_url.clone()))
+}
+
+/// A cURL share handle that lets transfers reuse each other's DNS lookups and TLS sessions.
+/// The `Multi` already pools connections for the handles added to it, this adds the rest.
+pub struct Share {
+    raw: *mut curl_sys::CURLSH,
+}
+
+impl Share {
+    pub fn new() -> Result<Self, curl::ShareError> {
+        curl::init();
+
+        // SAFETY: curl has been initialized, and a null handle is checked for below
+        let raw = unsafe { curl_sys::curl_share_init() };
+        if raw.is_null() {
+            return Err(curl::ShareError::new(curl_sys::CURLSHE_NOMEM));
+        }
+        let share = Self { raw };
+
+        for data in [
+            curl_sys::CURL_LOCK_DATA_DNS,
+            curl_sys::CURL_LOCK_DATA_SSL_SESSION,
+        ] {
+            // SAFETY: the handle is valid and CURLSHOPT_SHARE takes a lock data constant
+            let code =
+                unsafe { curl_sys::curl_share_setopt(share.raw, curl_sys::CURLSHOPT_SHARE, data) };
+            if code != curl_sys::CURLSHE_OK {
+                return Err(curl::ShareError::new(code));
+            }
+        }
+
+        Ok(share)
+    }
+
+    /// Has `easy` use the shared caches. The share has to outlive the handle's transfers.
+    pub fn attach<H>(&self, easy: &mut Easy2<H>) -> Result<(), curl::Error> {
+        // SAFETY: both handles are valid, and the share is only used from the thread that
+        // drives the transfers since it doesn't set any lock callbacks
+        let code =
+            unsafe { curl_sys::curl_easy_setopt(easy.raw(), curl_sys::CURLOPT_SHARE, self.raw) };
+        if code != curl_sys::CURLE_OK {
+            return Err(curl::Error::new(code));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from curl_share_init and is cleaned up exactly once
+        unsafe {
+            curl_sys::curl_share_cleanup(self.raw);
+        }
+    }
+}
+
+/// Receives the data for a single cURL transfer and writes it into the download's file.
+pub struct TransferHandler {
+    url: String,
+    /// Where the download is saved, which along with `url` finds it in the resume database.
+    target: PathBuf,
+    /// The URL the response came from, once redirects have been followed.
+    pub effective_url: String,
+    /// `None` for a request that only asks about the file, or until it's been named.
+    file: Option<File>,
+    /// The directory to create the file in once the response says what to call it.
+    name_in: Option<String>,
+    /// The paths the other downloads in the run are saving to, checked before naming the file.
+    claims: Arc<Claims>,
+    on_conflict: ConflictPolicy,
+    /// The name the file was given from the response.
+    pub file_name: Option<String>,
+    /// The name the response gave the file, before any rename for `on_conflict`.
+    pub served_name: Option<String>,
+    /// Set when the named file was already there and has to be skipped or resumed instead.
+    pub conflict: Option<Resolution>,
+    /// The byte offset the transfer asked the server to start from.
+    resume_from: u64,
+    /// The byte range being filled in when this is one segment of a segmented download.
+    segment: Option<Segment>,
+    /// Where the response body starts in the file.
+    pub offset: u64,
+    /// Bytes of the response body written to the file.
+    pub written: u64,
+    pub response: ResponseInfo,
+    pub error: Option<String>,
+    /// Set when a segment got the whole file back, so the download has to start over.
+    pub needs_restart: bool,
+    /// Hashes the file as it's written, when the download has a checksum to match.
+    hasher: Option<Hasher>,
+    /// Shared with the other transfers to cap how fast they all download together.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Set while cURL holds the transfer back for the rate limiter.
+    pub paused: bool,
+    /// The most bytes the file can have, from `--max-size`.
+    max_size: Option<u64>,
+    /// How many bytes to read and throw away when timing a mirror.
+    sample: Option<u64>,
+    checked: bool,
+    progress_bar: Arc<ProgressBar>,
+    db: Arc<Mutex<ResumeDb>>,
+    last_saved: Instant,
+}
+
+impl TransferHandler {
+    pub fn new(
+        url: String,
+        target: PathBuf,
+        file: File,
+        resume_from: u64,
+        progress_bar: Arc<ProgressBar>,
+        db: Arc<Mutex<ResumeDb>>,
+    ) -> Self {
+        Self {
+            effective_url: url.clone(),
+            url,
+            target,
+            file: Some(file),
+            name_in: None,
+            claims: Arc::default(),
+            on_conflict: ConflictPolicy::default(),
+            file_name: None,
+            served_name: None,
+            conflict: None,
+            resume_from,
+            segment: None,
+            offset: resume_from,
+            written: 0,
+            response: ResponseInfo::default(),
+            error: None,
+            needs_restart: false,
+            hasher: None,
+            rate_limiter: None,
+            paused: false,
+            max_size: None,
+            sample: None,
+            checked: false,
+            progress_bar,
+            db,
+            last_saved: Instant::now(),
+        }
+    }
+
+    /// A handler that fills in the rest of `segment`, sharing the download's progress bar.
+    pub fn for_segment(
+        url: String,
+        target: PathBuf,
+        file: File,
+        segment: Segment,
+        progress_bar: Arc<ProgressBar>,
+        db: Arc<Mutex<ResumeDb>>,
+    ) -> Self {
+        let mut handler = Self::new(
+            url,
+            target,
+            file,
+            segment.start + segment.downloaded,
+            progress_bar,
+            db,
+        );
+        handler.segment = Some(segment);
+
+        handler
+    }
+
+    /// A handler for a HEAD request that only collects the response headers.
+    pub fn probe(url: String, progress_bar: Arc<ProgressBar>, db: Arc<Mutex<ResumeDb>>) -> Self {
+        Self {
+            effective_url: url.clone(),
+            url,
+            target: PathBuf::new(),
+            file: None,
+            name_in: None,
+            claims: Arc::default(),
+            on_conflict: ConflictPolicy::default(),
+            file_name: None,
+            served_name: None,
+            conflict: None,
+            resume_from: 0,
+            segment: None,
+            offset: 0,
+            written: 0,
+            response: ResponseInfo::default(),
+            error: None,
+            needs_restart: false,
+            hasher: None,
+            rate_limiter: None,
+            paused: false,
+            max_size: None,
+            sample: None,
+            checked: false,
+            progress_bar,
+            db,
+            last_saved: Instant::now(),
+        }
+    }
+
+    /// A handler that reads up to `size` bytes of the file without keeping them, to time how
+    /// quickly the server at `url` sends them.
+    pub fn sample(
+        url: String,
+        size: u64,
+        progress_bar: Arc<ProgressBar>,
+        db: Arc<Mutex<ResumeDb>>,
+    ) -> Self {
+        let mut handler = Self::probe(url, progress_bar, db);
+        handler.sample = Some(size);
+
+        handler
+    }
+
+    /// The URL the transfer was asked for.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// A handler for a fresh download that creates its file in `dir` once the response
+    /// headers say what it should be called. Until then it's saved as `target`.
+    pub fn unnamed(
+        url: String,
+        target: PathBuf,
+        dir: String,
+        claims: Arc<Claims>,
+        on_conflict: ConflictPolicy,
+        progress_bar: Arc<ProgressBar>,
+        db: Arc<Mutex<ResumeDb>>,
+    ) -> Self {
+        let mut handler = Self::probe(url, progress_bar, db);
+        handler.target = target;
+        handler.name_in = Some(dir);
+        handler.claims = claims;
+        handler.on_conflict = on_conflict;
+
+        handler
+    }
+
+    /// Hashes the file with `algorithm` while it downloads. The part of the file already on
+    /// disk when resuming is read back in first, so the digest covers the whole file.
+    pub fn hash_with(&mut self, algorithm: Algorithm) {
+        self.hasher = Some(Hasher::new(algorithm));
+    }
+
+    /// Holds the transfer back whenever `rate_limiter` runs out of bytes.
+    pub fn limit_with(&mut self, rate_limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(rate_limiter);
+    }
+
+    /// Stops the transfer once the file would go past `max_size` bytes.
+    pub fn cap_size(&mut self, max_size: u64) {
+        self.max_size = Some(max_size);
+    }
+
+    /// Cuts the file off at the end of what's been downloaded, dropping any space that was
+    /// set aside past it.
+    pub fn trim_file(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.set_len(self.downloaded()),
+            None => Ok(()),
+        }
+    }
+
+    /// The digest of the whole file, if it was hashed while it downloaded.
+    pub fn take_digest(&mut self) -> Option<String> {
+        if !self.checked {
+            return None;
+        }
+
+        self.hasher.take().map(Hasher::finalize)
+    }
+
+    /// How many bytes of the remote file are now on disk.
+    pub fn downloaded(&self) -> u64 {
+        self.offset + self.written
+    }
+
+    /// The segment with the bytes written by this transfer counted in.
+    pub fn segment(&self) -> Option<Segment> {
+        self.segment.map(|segment| Segment {
+            downloaded: self.downloaded() - segment.start,
+            ..segment
+        })
+    }
+
+    /// Works out why the transfer failed with `error`.
+    pub fn failure(&self, error: &curl::Error) -> Failure {
+        // Our own errors come from the response not lining up with the file, which
+        // asking again won't fix
+        if let Some(message) = &self.error {
+            return Failure::permanent(message.clone());
+        }
+
+        if error.is_http_returned_error() {
+            let status = self.response.status;
+            return Failure {
+                message: format!("The server responded with HTTP {status}"),
+                transient: matches!(status, 408 | 425 | 429 | 500 | 502 | 503 | 504),
+                retry_after: self.response.retry_after,
+                from_server: true,
+            };
+        }
+
+        // A stalled transfer and one that ran out of time are both timeouts to cURL
+        let message = match error.extra_description() {
+            Some(description)
+                if error.is_operation_timedout()
+                    && description.starts_with("Operation too slow") =>
+            {
+                format!("The transfer stalled: {description}")
+            }
+            _ => error.to_string(),
+        };
+
+        Failure {
+            message,
+            transient: error.is_couldnt_connect()
+                || error.is_couldnt_resolve_host()
+                || error.is_couldnt_resolve_proxy()
+                || error.is_operation_timedout()
+                || error.is_recv_error()
+                || error.is_send_error()
+                || error.is_partial_file()
+                || error.is_got_nothing()
+                || error.is_http2_error()
+                || error.is_http2_stream_error()
+                || error.is_ssl_connect_error(),
+            retry_after: None,
+            from_server: true,
+        }
+    }
+
+    /// Makes sure the response body lines up with what is already on disk before anything is
+    /// written, restarting from the beginning if the server sent the whole file instead.
+    fn check_response(&mut self) -> Result<(), String> {
+        if let Some(dir) = self.name_in.take() {
+            self.create_named_file(&dir)?;
+        }
+
+        let Some(file) = self.file.as_mut() else {
+            return Err("No file to write the download to".to_string());
+        };
+
+        if self.resume_from > 0 || self.segment.is_some() {
+            let start = self.response.content_range.and_then(|range| range.start);
+            match (self.response.status, start) {
+                (206, Some(start)) if start == self.resume_from => {
+                    // Catch the hash up on what was downloaded before
+                    if let Some(hasher) = self.hasher.as_mut() {
+                        file.seek(SeekFrom::Start(0))
+                            .and_then(|_| hasher.update_from((&*file).take(start)))
+                            .map_err(|e| format!("Could not hash the partial file: {e}"))?;
+                    }
+
+                    file.seek(SeekFrom::Start(start))
+                        .map_err(|e| format!("Could not seek in the partial file: {e}"))?;
+                }
+                (206, Some(start)) => {
+                    return Err(format!(
+                        "The server resumed from byte {start} instead of byte {}",
+                        self.resume_from
+                    ));
+                }
+                (206, _) => {
+                    return Err(
+                        "The server sent a partial response without a usable Content-Range"
+                            .to_string(),
+                    );
+                }
+                _ if self.segment.is_some() => {
+                    // The other segments are already writing into the file, so this one
+                    // can't just take over with the whole body
+                    self.needs_restart = true;
+                    return Err(
+                        "The server sent the whole file instead of the requested segment"
+                            .to_string(),
+                    );
+                }
+                _ => {
+                    // Either ranges aren't supported or If-Range said the file changed,
+                    // so the body is the whole file and the partial one has to go
+                    file.set_len(0)
+                        .and_then(|_| file.seek(SeekFrom::Start(0)))
+                        .map_err(|e| format!("Could not truncate the partial file: {e}"))?;
+                    self.offset = 0;
+                    if let Some(hasher) = self.hasher.as_mut() {
+                        *hasher = Hasher::new(hasher.algorithm());
+                    }
+                    self.progress_bar.println(format!(
+                        "{} can't be resumed from byte {}, restarting the download.",
+                        self.url, self.resume_from
+                    ));
+                }
+            }
+        }
+
+        // Segments share one progress bar that was already sized for the whole file
+        if self.segment.is_none() {
+            if let Some(content_length) = self.response.content_length {
+                self.progress_bar.set_length(self.offset + content_length);
+            }
+            self.progress_bar.set_position(self.offset);
+        }
+
+        // Remember the validators so a later resume can tell if the file changed
+        if let Err(e) = self.db.lock().unwrap().update_validators(
+            &self.url,
+            &self.target,
+            self.response.etag.as_deref(),
+            self.response.last_modified.as_deref(),
+        ) {
+            self.progress_bar.println(format!(
+                "Could not save the ETag for {} to the resume database: {e}",
+                self.url
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Names the file after the response and creates it in `dir`.
+    fn create_named_file(&mut self, dir: &str) -> Result<(), String> {
+        let file_name = file_name_from_response(&self.response, &self.effective_url)
+            .or_else(|| file_name_from_url(&self.url))
+            .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
+
+        self.served_name = Some(file_name.clone());
+        let file_name = match self
+            .claims
+            .resolve(self.on_conflict, dir, &file_name, false)?
+        {
+            Resolution::Create(file_name) => file_name,
+            // Skipping or resuming the file that's there needs the transfer to stop here
+            resolution => {
+                self.save_file_name(dir, &file_name);
+                self.file_name = Some(file_name.clone());
+                self.conflict = Some(resolution);
+                return Err(format!("{file_name} already exists"));
+            }
+        };
+
+        let file = File::create(Path::new(dir).join(part_file_name(&file_name)))
+            .map_err(|e| format!("Could not create {file_name}: {e}"))?;
+        self.file = Some(file);
+
+        self.progress_bar.set_message(file_name.clone());
+        self.save_file_name(dir, &file_name);
+        self.file_name = Some(file_name);
+
+        Ok(())
+    }
+
+    /// Moves the download's entry in the resume database over to the name it was given.
+    fn save_file_name(&mut self, dir: &str, file_name: &str) {
+        if let Err(e) = self
+            .db
+            .lock()
+            .unwrap()
+            .update_file_name(&self.url, &self.target, file_name)
+        {
+            self.progress_bar.println(format!(
+                "Could not save the file name for {} to the resume database: {e}",
+                self.url
+            ));
+        }
+        self.target = Path::new(dir).join(file_name);
+    }
+
+    /// Writes the current byte offset to the resume database.
+    fn save_progress(&mut self) {
+        let db = self.db.lock().unwrap();
+        let _ = match self.segment() {
+            Some(segment) => db.update_segment(&self.url, &self.target, &segment),
+            None => db.update_progress(&self.url, &self.target, self.downloaded()),
+        };
+    }
+}
+
+impl Handler for TransferHandler {
+    fn header(&mut self, data: &[u8]) -> bool {
+        // Keep track of where redirects lead so the file can be named after the final URL
+        self.response
+            .track_header_line(data, &mut self.effective_url);
+        true
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        // A sample is cut off once it has enough to time the server by
+        if let Some(size) = self.sample {
+            self.written += data.len() as u64;
+            return Ok(if self.written >= size { 0 } else { data.len() });
+        }
+
+        // A probe only wants the headers, so stop it at the body
+        if self.file.is_none() && self.name_in.is_none() {
+            return Ok(0);
+        }
+
+        // cURL hands the same data back once the transfer is unpaused
+        if let Some(rate_limiter) = &self.rate_limiter
+            && !rate_limiter.take(data.len())
+        {
+            self.paused = true;
+            return Err(WriteError::Pause);
+        }
+
+        if !self.checked {
+            if let Err(e) = self.check_response() {
+                self.error = Some(e);
+                // Returning less than the data's length makes cURL abort the transfer
+                return Ok(0);
+            }
+            self.checked = true;
+        }
+
+        // Never let a segment spill over into the next one
+        if let Some(segment) = self.segment
+            && self.downloaded() + data.len() as u64 > segment.end + 1
+        {
+            self.error = Some("The server sent more data than the segment asked for".to_string());
+            return Ok(0);
+        }
+
+        if let Some(max_size) = self.max_size
+            && self.downloaded() + data.len() as u64 > max_size
+        {
+            self.error = Some(format!(
+                "The file is bigger than the --max-size of {}",
+                HumanBytes(max_size)
+            ));
+            return Ok(0);
+        }
+
+        let Some(file) = self.file.as_mut() else {
+            return Ok(0);
+        };
+
+        match file.write_all(data) {
+            Ok(_) => {
+                self.written += data.len() as u64;
+                self.progress_bar.inc(data.len() as u64);
+                if let Some(hasher) = self.hasher.as_mut() {
+                    hasher.update(data);
+                }
+                Ok(data.len())
+            }
+            Err(e) => {
+                self.error = Some(format!("Error writing download to the file: {e}"));
+                Ok(0)
+            }
+        }
+    }
+
+    fn progress(&mut self, _dltotal: f64, _dlnow: f64, _ultotal: f64, _ulnow: f64) -> bool {
+        // Periodically save how far along the download is
+        if self.checked && self.last_saved.elapsed() >= PROGRESS_SAVE_INTERVAL {
+            self.save_progress();
+            self.last_saved = Instant::now();
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_content_range() {
+        assert_eq!(
+            ContentRange::parse("bytes 100-199/1000"),
+            Some(ContentRange {
+                start: Some(100),
+                end: Some(199),
+                total: Some(1000),
+            })
+        );
+        assert_eq!(
+            ContentRange::parse("bytes 0-99/*"),
+            Some(ContentRange {
+                start: Some(0),
+                end: Some(99),
+                total: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_an_unsatisfied_range_with_or_without_a_total() {
+        assert_eq!(
+            ContentRange::parse("bytes */1000"),
+            Some(ContentRange {
+                start: None,
+                end: None,
+                total: Some(1000),
+            })
+        );
+        assert_eq!(
+            ContentRange::parse("bytes */*"),
+            Some(ContentRange {
+                start: None,
+                end: None,
+                total: None,
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_content_ranges() {
+        for value in [
+            "",
+            "bytes",
+            "bytes 0-99",
+            "bytes 0/100",
+            "bytes a-b/100",
+            "bytes 0-99/many",
+            "items 0-99/100",
+            "bytes -5-99/100",
+        ] {
+            assert_eq!(ContentRange::parse(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn splits_a_file_into_contiguous_segments() {
+        let size = 10 * MIN_SEGMENT_SIZE + 3;
+        let segments = Segment::split(size, 4);
+
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[0].start, 0);
+        assert_eq!(segments.last().unwrap().end, size - 1);
+        for pair in segments.windows(2) {
+            assert_eq!(pair[0].end + 1, pair[1].start);
+        }
+        assert_eq!(segments.iter().map(Segment::size).sum::<u64>(), size);
+        assert!(segments.iter().all(|segment| segment.downloaded == 0));
+    }
+
+    #[test]
+    fn keeps_segments_at_least_the_minimum_size() {
+        assert_eq!(Segment::split(3 * MIN_SEGMENT_SIZE, 8).len(), 3);
+
+        let segments = Segment::split(100, 8);
+        assert_eq!(
+            segments,
+            vec![Segment {
+                index: 0,
+                start: 0,
+                end: 99,
+                downloaded: 0,
+            }]
+        );
+        assert_eq!(Segment::split(2 * MIN_SEGMENT_SIZE, 0).len(), 1);
+    }
+
+    #[test]
+    fn reads_retry_after_as_seconds_or_a_date() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("0"), Some(Duration::ZERO));
+
+        // A date that has already passed means retrying right away
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(600));
+        let delay = parse_retry_after(&later).unwrap();
+        assert!(delay > Duration::from_secs(590) && delay <= Duration::from_secs(600));
+    }
+
+    #[test]
+    fn ignores_a_malformed_retry_after() {
+        for value in ["", "soon", "-5", "1.5", "Someday, 99 Foo 2015"] {
+            assert_eq!(parse_retry_after(value), None, "{value:?}");
+        }
+    }
+}